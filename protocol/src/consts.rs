@@ -0,0 +1,88 @@
+//Serial port settings
+pub const BAUD: u32 = 57600;
+
+pub const MSG_OK: u8 = 0xFF;
+pub const MSG_ERROR: u8 = 0xFE;
+/// Followed by an error code and the offending instruction, only sent after `RQ_SET_ERROR_MODE`
+pub const MSG_EXTENDED_ERROR: u8 = 0xFD;
+/// Starts a block of samples pushed by the board between `RQ_STREAM_START` and `RQ_STREAM_STOP`
+pub const MSG_STREAM_BLOCK: u8 = 0xFC;
+/// Reported as offending instruction when the failure happened before one was received
+pub const NO_INSTRUCTION: u8 = 0xFF;
+pub const MAX_DATA_SIZE: u8 = 64;
+/// Upper limit for the step count of `RQ_ADC_DAC_STROKE`, all samples are buffered
+pub const MAX_STROKE_STEPS: u16 = 1024;
+/// Upper limit for `steps * settle_time_us` of `RQ_ADC_DAC_STROKE`, the board answers nothing
+/// else while it sweeps
+pub const MAX_STROKE_DURATION_US: u32 = 1_000_000;
+/// Frequency range accepted by `RQ_PWM_SET_FREQ`, the resolution drops with higher frequencies
+/// down to 8 bits at the maximum on an 80 MHz clock
+pub const PWM_MIN_FREQUENCY: u32 = 10;
+pub const PWM_MAX_FREQUENCY: u32 = 312_500;
+/// Mean and standard deviation of `RQ_ANALOG_READ_STATS` are fixed point with this many
+/// fractional bits
+pub const STATS_FRACTION_BITS: u32 = 4;
+/// Upper limit for `samples * interval_us` of `RQ_ANALOG_READ_STATS`, the board answers
+/// nothing else while it samples. Back to back sampling is bounded by the sample count alone.
+pub const MAX_STATS_DURATION_US: u32 = 1_000_000;
+/// Sample rates the continuous ADC supports for `RQ_STREAM_START`, at 57600 baud only about
+/// 2800 samples per second make it to the host, the rest is dropped
+pub const STREAM_MIN_SAMPLE_RATE: u32 = 611;
+pub const STREAM_MAX_SAMPLE_RATE: u32 = 83_333;
+/// Upper limit for the samples in one `MSG_STREAM_BLOCK`
+pub const MAX_STREAM_BLOCK_SAMPLES: u8 = 64;
+
+// Data space addresses of the ATmega1284 I/O registers the B15 host library accesses directly,
+// `RQ_SET_MEM_8` and friends map them onto the digital ports
+pub const REG_PINA: u16 = 0x20;
+pub const REG_DDRA: u16 = 0x21;
+pub const REG_PORTA: u16 = 0x22;
+pub const REG_PINB: u16 = 0x23;
+pub const REG_DDRB: u16 = 0x24;
+pub const REG_PORTB: u16 = 0x25;
+
+//Requests
+pub const RQ_DISCARD: u8 = 0;
+pub const RQ_TEST: u8 = 1;
+pub const RQ_INFO: u8 = 2;
+pub const RQ_INT_TEST: u8 = 3;
+pub const RQ_SELF_TEST: u8 = 4;
+pub const RQ_DIGITAL_WRITE_0: u8 = 5;
+pub const RQ_DIGITAL_WRITE_1: u8 = 6;
+pub const RQ_DIGITAL_READ_0: u8 = 7;
+pub const RQ_DIGITAL_READ_1: u8 = 8;
+pub const RQ_READ_DIP_SWITCH: u8 = 9;
+pub const RQ_ANALOG_WRITE_0: u8 = 10;
+pub const RQ_ANALOG_WRITE_1: u8 = 11;
+pub const RQ_ANALOG_READ: u8 = 12;
+pub const RQ_ADC_DAC_STROKE: u8 = 13;
+pub const RQ_PWM_SET_FREQ: u8 = 14;
+pub const RQ_PWM_SET_VALUE: u8 = 15;
+pub const RQ_SET_MEM_8: u8 = 16;
+pub const RQ_GET_MEM_8: u8 = 17;
+pub const RQ_SET_MEM_16: u8 = 18;
+pub const RQ_GET_MEM_16: u8 = 19;
+// 20 to 63 are left to the B15 firmware, b32 extensions start at 64
+pub const RQ_SET_ERROR_MODE: u8 = 0x40;
+pub const RQ_CAPABILITIES: u8 = 0x41;
+pub const RQ_SET_ADC_MODE: u8 = 0x42;
+pub const RQ_ANALOG_READ_MV: u8 = 0x43;
+pub const RQ_SET_ATTENUATION: u8 = 0x44;
+pub const RQ_FULL_SCALE: u8 = 0x45;
+pub const RQ_ANALOG_READ_STATS: u8 = 0x46;
+pub const RQ_STREAM_START: u8 = 0x47;
+pub const RQ_STREAM_STOP: u8 = 0x48;
+pub const RQ_ANALOG_READ_ALL: u8 = 0x49;
+pub const RQ_SET_DIRECTION_0: u8 = 0x4A;
+pub const RQ_SET_DIRECTION_1: u8 = 0x4B;
+pub const RQ_SET_PULL_0: u8 = 0x4C;
+pub const RQ_SET_PULL_1: u8 = 0x4D;
+pub const RQ_DIGITAL_WRITE_MASKED_0: u8 = 0x4E;
+pub const RQ_DIGITAL_WRITE_MASKED_1: u8 = 0x4F;
+pub const RQ_DIGITAL_SET_BITS_0: u8 = 0x50;
+pub const RQ_DIGITAL_SET_BITS_1: u8 = 0x51;
+pub const RQ_DIGITAL_CLEAR_BITS_0: u8 = 0x52;
+pub const RQ_DIGITAL_CLEAR_BITS_1: u8 = 0x53;
+pub const RQ_DIGITAL_TOGGLE_BITS_0: u8 = 0x54;
+pub const RQ_DIGITAL_TOGGLE_BITS_1: u8 = 0x55;
+pub const RQ_SET_INTER_BYTE_TIMEOUT: u8 = 0x56;
@@ -0,0 +1,60 @@
+//! Bit order of digital port values
+//!
+//! Bit i is the i-th pin of the side in [crate::Request::DigitalWrite],
+//! [crate::Response::DigitalValue], [crate::BitUpdate], [crate::Request::SetDirection] and
+//! [crate::Request::SetPull] alike:
+//!
+//! | Bit              | 7    | 6    | 5     | 4     | 3     | 2     | 1     | 0     |
+//! |------------------|------|------|-------|-------|-------|-------|-------|-------|
+//! | Side A (`Port1`) | -    | a7_d | a5_ad | a4_ad | a3_ad | a2_ad | a1_ad | a0_ad |
+//! | Side B (`Port2`) | b7_d | b6_d | b5_d  | b4_d  | b3_d  | b2_d  | b1_d  | b0_d  |
+//!
+//! Side A has no a6, a7_d moves up to bit 6 and bit 7 always reads low.
+
+/// Packs the levels of the pins of a side, `levels[i]` becomes bit i
+pub fn pack_levels(levels: &[bool]) -> u8 {
+    levels
+        .iter()
+        .take(u8::BITS as usize)
+        .enumerate()
+        .fold(0, |value, (bit, high)| value | (u8::from(*high) << bit))
+}
+
+/// Levels of the `N` pins of a side in `value`, the inverse of [pack_levels]
+pub fn unpack_levels<const N: usize>(value: u8) -> [bool; N] {
+    core::array::from_fn(|bit| bit < u8::BITS as usize && value & (1 << bit) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_i_is_pin_i() {
+        assert_eq!(
+            pack_levels(&[true, false, false, false, false, false, false]),
+            0x01
+        );
+        assert_eq!(
+            pack_levels(&[false, false, false, false, false, false, true]),
+            0x40
+        );
+        assert_eq!(
+            unpack_levels::<7>(0x40),
+            [false, false, false, false, false, false, true]
+        );
+        assert_eq!(
+            unpack_levels::<8>(0x80),
+            [false, false, false, false, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn write_then_read_round_trips() {
+        for value in 0..=u8::MAX {
+            // Side A drops bit 7 for lack of an eighth pin
+            assert_eq!(pack_levels(&unpack_levels::<7>(value)), value & 0x7F);
+            assert_eq!(pack_levels(&unpack_levels::<8>(value)), value);
+        }
+    }
+}
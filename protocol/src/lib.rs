@@ -0,0 +1,315 @@
+//! Wire protocol spoken between a b32 board and its host
+//!
+//! Every exchange is started by the host with an instruction byte from [consts] followed by
+//! its little endian payload and answered by the board with exactly one response.
+//! The board side uses [read_request] and [write_response], hosts use [write_request] and
+//! [read_response].
+//!
+//! The crate builds for the host as well, so the codec tests live here instead of the firmware.
+#![no_std]
+
+extern crate alloc;
+
+pub mod consts;
+mod levels;
+mod registers;
+mod request;
+mod response;
+mod stats;
+mod timeout;
+
+use alloc::format;
+use embedded_io_async::{Error as _, ErrorKind, Read, ReadExactError, Write};
+use error_stack::Report;
+use thiserror::Error;
+
+pub use levels::*;
+pub use registers::*;
+pub use request::*;
+pub use response::*;
+pub use stats::*;
+
+#[derive(Debug, Error)]
+pub enum CommunicationError {
+    #[error("read error")]
+    ReadError,
+    #[error("write error")]
+    WriteError,
+    #[error("invalid response")]
+    InvalidResponse,
+    /// The rest of a frame did not arrive in time, the bytes received so far are lost
+    #[error("timed out waiting for the rest of the frame")]
+    Timeout,
+}
+
+impl CommunicationError {
+    /// Code reported to hosts that enabled [ErrorMode::Extended]
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            CommunicationError::Timeout => ErrorCode::Timeout,
+            CommunicationError::ReadError
+            | CommunicationError::WriteError
+            | CommunicationError::InvalidResponse => ErrorCode::PeripheralFailure,
+        }
+    }
+}
+
+/// Attached to errors that happened while receiving the payload of this instruction
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Instruction(pub u8);
+
+impl core::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "instruction {:#x}", self.0)
+    }
+}
+
+async fn read_array<R: Read, const N: usize>(
+    reader: &mut R,
+) -> error_stack::Result<[u8; N], CommunicationError> {
+    let mut buffer = [0u8; N];
+    // The io errors only implement `Error` with std, so they are attached as text
+    reader.read_exact(&mut buffer).await.map_err(|error| {
+        let context = match &error {
+            ReadExactError::Other(error) if error.kind() == ErrorKind::TimedOut => {
+                CommunicationError::Timeout
+            }
+            _ => CommunicationError::ReadError,
+        };
+        Report::new(context).attach_printable(format!("{error:?}"))
+    })?;
+    Ok(buffer)
+}
+
+async fn write_all<W: Write>(
+    writer: &mut W,
+    bytes: &[u8],
+) -> error_stack::Result<(), CommunicationError> {
+    writer.write_all(bytes).await.map_err(|error| {
+        Report::new(CommunicationError::WriteError).attach_printable(format!("{error:?}"))
+    })
+}
+
+async fn flush<W: Write>(writer: &mut W) -> error_stack::Result<(), CommunicationError> {
+    writer.flush().await.map_err(|error| {
+        Report::new(CommunicationError::WriteError).attach_printable(format!("{error:?}"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use embassy_futures::block_on;
+
+    fn round_trip(request: Request, response: Response) {
+        let mut request_bytes = Vec::new();
+        block_on(write_request(&mut request_bytes, &request)).expect("encoding request failed");
+        let mut reader = request_bytes.as_slice();
+        let decoded = block_on(read_request(&mut reader)).expect("decoding request failed");
+        assert!(reader.is_empty(), "request was not consumed completely");
+        assert_eq!(decoded.as_ref(), Ok(&request));
+
+        let mut response_bytes = Vec::new();
+        block_on(write_response(&mut response_bytes, response.clone()))
+            .expect("encoding response failed");
+        let mut reader = response_bytes.as_slice();
+        let decoded =
+            block_on(read_response(&mut reader, &request)).expect("decoding response failed");
+        assert!(reader.is_empty(), "response was not consumed completely");
+        assert_eq!(decoded, response);
+    }
+
+    #[test]
+    fn round_trip_all_requests() {
+        let info = BoardInfo {
+            firmware_version: (0, 1, 0),
+            build_profile: BuildProfile::Debug,
+            runtime: Runtime::Tokio,
+            chip_model: 13,
+            chip_revision: 1,
+            idf_version: (5, 2, 2),
+            serial_number: [1, 2, 3, 4, 5, 6],
+        };
+        let cases = [
+            (Request::Discard, Response::Ok),
+            (Request::InitTest(7), Response::TestEcho(7)),
+            (Request::Info, Response::Info(info)),
+            (Request::IntTest(100), Response::IntTestResult(300)),
+            (
+                Request::SelfTest,
+                Response::SelfTest {
+                    a_to_b: 0x7F,
+                    b_to_a: 0x70,
+                },
+            ),
+            (Request::ReadDipSwitch, Response::DigitalValue(0b101)),
+            (
+                Request::AnalogWrite(AnalogWritePort::Port2, 512),
+                Response::Ok,
+            ),
+            (
+                Request::AnalogRead(AnalogReadPort::Port3),
+                Response::AnalogValue(2048),
+            ),
+            (
+                Request::AdcDacStroke {
+                    write_port: AnalogWritePort::Port1,
+                    read_port: AnalogReadPort::Port5,
+                    steps: 3,
+                    settle_time_us: 10,
+                },
+                Response::AnalogValues(vec![0, 1000, 2000]),
+            ),
+            (
+                Request::PwmSetFrequency(1_000),
+                Response::PwmFrequency {
+                    frequency: 1_000,
+                    top: 0x3FFF,
+                },
+            ),
+            (Request::PwmSetValue(42), Response::Ok),
+            (
+                Request::DigitalWrite(DigitalPort::Port1, 0x55),
+                Response::Ok,
+            ),
+            (
+                Request::DigitalRead(DigitalPort::Port2),
+                Response::DigitalValue(0xAA),
+            ),
+            (Request::SetErrorMode(ErrorMode::Extended), Response::Ok),
+            (Request::SetInterByteTimeout(250), Response::Ok),
+            (Request::SetAdcMode(AdcMode::B15), Response::Ok),
+            (
+                Request::AnalogReadMillivolts(AnalogReadPort::Port2),
+                Response::Millivolts(1650),
+            ),
+            (
+                Request::SetAttenuation(AnalogReadPort::Port1, Attenuation::Db6),
+                Response::Ok,
+            ),
+            (
+                Request::FullScale(AnalogReadPort::Port1),
+                Response::Millivolts(1750),
+            ),
+            (
+                Request::AnalogReadStats {
+                    port: AnalogReadPort::Port2,
+                    samples: 64,
+                    interval_us: 0,
+                },
+                Response::AnalogStats(AnalogStats {
+                    mean: 2048 << consts::STATS_FRACTION_BITS,
+                    min: 2040,
+                    max: 2056,
+                    std_dev: 40,
+                }),
+            ),
+            (
+                Request::StreamStart {
+                    ports: 0b11,
+                    sample_rate: 2_000,
+                },
+                Response::Ok,
+            ),
+            (Request::StreamStop, Response::StreamStopped { dropped: 12 }),
+            (
+                Request::SetDirection(DigitalPort::Port2, 0xF0),
+                Response::Ok,
+            ),
+            (
+                Request::SetPull {
+                    port: DigitalPort::Port1,
+                    pull_up: 0x01,
+                    pull_down: 0x02,
+                },
+                Response::Ok,
+            ),
+            (
+                Request::DigitalUpdate(
+                    DigitalPort::Port1,
+                    BitUpdate::Masked {
+                        value: 0x12,
+                        mask: 0x30,
+                    },
+                ),
+                Response::Ok,
+            ),
+            (
+                Request::DigitalUpdate(DigitalPort::Port2, BitUpdate::Set(0x04)),
+                Response::Ok,
+            ),
+            (
+                Request::DigitalUpdate(DigitalPort::Port1, BitUpdate::Clear(0x08)),
+                Response::Ok,
+            ),
+            (
+                Request::DigitalUpdate(DigitalPort::Port2, BitUpdate::Toggle(0xFF)),
+                Response::Ok,
+            ),
+            (
+                Request::SetMem8 {
+                    address: consts::REG_DDRA,
+                    value: 0x0F,
+                },
+                Response::Memory8(0x0F),
+            ),
+            (
+                Request::GetMem8 {
+                    address: consts::REG_PINA,
+                },
+                Response::Memory8(0x03),
+            ),
+            (
+                Request::SetMem16 {
+                    address: consts::REG_PINB,
+                    value: 0xFF00,
+                },
+                Response::Memory16(0xFF00),
+            ),
+            (
+                Request::GetMem16 {
+                    address: consts::REG_DDRB,
+                },
+                Response::Memory16(0x00FF),
+            ),
+            (
+                Request::AnalogReadAll,
+                Response::AnalogScan {
+                    ports: 0x3F,
+                    values: vec![0, 1, 2, 3, 4, 4095],
+                },
+            ),
+            (
+                Request::Capabilities,
+                Response::Capabilities(Capabilities {
+                    analog_read_ports: 0x3F,
+                    analog_write_ports: 0x03,
+                    digital_port1_pins: 0x7F,
+                    digital_port2_pins: 0xFF,
+                    dip_switches: 0x01,
+                }),
+            ),
+        ];
+        for (request, response) in cases {
+            round_trip(request, response);
+        }
+    }
+
+    #[test]
+    fn round_trip_errors() {
+        let extended = ErrorMode::Extended.response(ErrorCode::PeripheralFailure, consts::RQ_INFO);
+        round_trip(Request::Info, extended);
+        round_trip(
+            Request::SetErrorMode(ErrorMode::Extended),
+            ErrorMode::Legacy.response(ErrorCode::InvalidArgument, consts::RQ_SET_ERROR_MODE),
+        );
+        round_trip(Request::Discard, Response::Error);
+        round_trip(Request::InitTest(1), Response::Error);
+        round_trip(Request::Info, Response::Error);
+        round_trip(Request::SelfTest, Response::Error);
+        round_trip(Request::PwmSetFrequency(50), Response::Error);
+        round_trip(Request::PwmSetValue(1), Response::Error);
+    }
+}
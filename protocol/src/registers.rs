@@ -0,0 +1,82 @@
+//! State of the emulated AVR ports behind [crate::Request::SetMem8] and friends
+
+use crate::AvrRegister;
+
+/// Pin configuration of one side, kept by the board outside its drivers so it outlives a
+/// switch to analog mode or a self-test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRegisters {
+    /// Set bits are outputs, in the bit order of [crate::pack_levels]
+    pub direction: u8,
+    /// Levels of the outputs and pull-ups of the inputs
+    pub output: u8,
+    pub pull_up: u8,
+    pub pull_down: u8,
+}
+
+impl PortRegisters {
+    /// Unlike the ATmega1284, which resets DDRx to 0x00, all pins start as outputs to keep
+    /// `digital_write` working without a direction set first. B15 hosts that rely on the reset
+    /// value have to write DDRx.
+    pub const RESET: Self = Self {
+        direction: 0xFF,
+        output: 0,
+        pull_up: 0,
+        pull_down: 0,
+    };
+
+    /// Stores a register write, the board then applies the changed fields to its pins
+    pub fn write(&mut self, register: AvrRegister, value: u8) {
+        match register {
+            AvrRegister::Ddr(_) => self.direction = value,
+            // Writing PINx toggles PORTx on the ATmega1284
+            AvrRegister::Pin(_) => self.output ^= value,
+            AvrRegister::Port(_) => self.output = value,
+        }
+        // Inputs with their PORTx bit set pull up, whichever of both registers changed.
+        // Pull-downs come from SetPull only, PORTx has no say in them.
+        self.pull_up = self.output & !self.direction;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DigitalPort;
+
+    #[test]
+    fn port_then_ddr_enables_pull_ups() {
+        let mut registers = PortRegisters::RESET;
+        registers.write(AvrRegister::Port(DigitalPort::Port1), 0x0F);
+        assert_eq!(registers.pull_up, 0x00);
+        registers.write(AvrRegister::Ddr(DigitalPort::Port1), 0x03);
+        assert_eq!(registers.direction, 0x03);
+        assert_eq!(registers.pull_up, 0x0C);
+    }
+
+    #[test]
+    fn ddr_then_port_enables_pull_ups() {
+        let mut registers = PortRegisters::RESET;
+        registers.write(AvrRegister::Ddr(DigitalPort::Port2), 0x03);
+        registers.write(AvrRegister::Port(DigitalPort::Port2), 0x0F);
+        assert_eq!(registers.pull_up, 0x0C);
+    }
+
+    #[test]
+    fn outputs_drop_their_pull_ups() {
+        let mut registers = PortRegisters::RESET;
+        registers.write(AvrRegister::Ddr(DigitalPort::Port1), 0x00);
+        registers.write(AvrRegister::Port(DigitalPort::Port1), 0xFF);
+        assert_eq!(registers.pull_up, 0xFF);
+        registers.write(AvrRegister::Ddr(DigitalPort::Port1), 0xF0);
+        assert_eq!(registers.pull_up, 0x0F);
+    }
+
+    #[test]
+    fn pin_write_toggles_port() {
+        let mut registers = PortRegisters::RESET;
+        registers.write(AvrRegister::Port(DigitalPort::Port1), 0x05);
+        registers.write(AvrRegister::Pin(DigitalPort::Port1), 0x03);
+        assert_eq!(registers.output, 0x06);
+    }
+}
@@ -0,0 +1,1366 @@
+use crate::timeout::InterByteTimeout;
+use crate::Instruction;
+use crate::{consts, flush, read_array, write_all, CommunicationError, ErrorCode, ErrorMode};
+use embedded_hal_async::delay::DelayNs;
+use embedded_io_async::{Read, Write};
+use log::{info, warn};
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Request {
+    /// Drops whatever the board received so far and returns the outputs, pins and analog
+    /// configuration to their reset state. The ADC mode is a stored setting and stays.
+    Discard,
+    InitTest(u8),
+    Info,
+    IntTest(u16),
+    SelfTest,
+    /// Reads the DIP switches, bit n is set while switch n is on. Boards without switch n,
+    /// see [Capabilities::dip_switches](crate::Capabilities::dip_switches), leave it clear.
+    ReadDipSwitch,
+    AnalogWrite(AnalogWritePort, u16),
+    AnalogRead(AnalogReadPort),
+    /// Sweeps an analog output from 0 to full scale and samples an analog input after each step
+    AdcDacStroke {
+        write_port: AnalogWritePort,
+        read_port: AnalogReadPort,
+        steps: u16,
+        settle_time_us: u16,
+    },
+    /// 0 turns the PWM output off
+    PwmSetFrequency(u32),
+    PwmSetValue(u16),
+    /// Bit i drives the i-th pin of the port, see [crate::pack_levels] for the layout
+    DigitalWrite(DigitalPort, u8),
+    /// Answered in the bit order of [Request::DigitalWrite]
+    DigitalRead(DigitalPort),
+    SetErrorMode(ErrorMode),
+    /// Longest gap in milliseconds between two bytes of a request before the board discards
+    /// it, 0 waits forever. Applies from the next request on and falls back to the board's
+    /// default on [Request::Discard].
+    SetInterByteTimeout(u16),
+    /// Asks which ports are actually available on this board
+    Capabilities,
+    /// Selects the scale of analog readings, the board keeps it across reboots
+    SetAdcMode(AdcMode),
+    /// Reads the calibrated voltage at the pin regardless of [AdcMode]. Chips without
+    /// calibration data in their eFuses answer [ErrorCode::Unsupported].
+    AnalogReadMillivolts(AnalogReadPort),
+    /// Changes the input range of one analog read port, the others keep their setting.
+    /// There is no bit width setting, the ADC of the ESP32-C6 always converts with 12 bits.
+    SetAttenuation(AnalogReadPort, Attenuation),
+    /// Asks for the voltage a port reads at full scale with its current attenuation, needs
+    /// calibration data like [Request::AnalogReadMillivolts]
+    FullScale(AnalogReadPort),
+    /// Samples a port repeatedly in the current [AdcMode] and answers with statistics only
+    AnalogReadStats {
+        port: AnalogReadPort,
+        samples: u16,
+        /// 0 samples back to back
+        interval_us: u16,
+    },
+    /// Starts pushing raw samples of the ports in the mask until [Request::StreamStop] or
+    /// [Request::Discard], other instructions are ignored in the meantime
+    StreamStart {
+        /// Bit n selects `AnalogReadPort` n + 1
+        ports: u8,
+        /// Samples per second over all selected ports
+        sample_rate: u32,
+    },
+    StreamStop,
+    /// Reads every available analog read port back to back in the current [AdcMode]
+    AnalogReadAll,
+    /// Pins with a set bit drive their level, the others become inputs, in the bit order of
+    /// [Request::DigitalWrite]
+    SetDirection(DigitalPort, u8),
+    /// Enables the pull resistors of the pins with a set bit, both for a pin enables both.
+    /// Bits as in [Request::DigitalWrite], the setting lasts until [Request::Discard].
+    SetPull {
+        port: DigitalPort,
+        pull_up: u8,
+        pull_down: u8,
+    },
+    /// Changes some outputs of a port and keeps the others at the level last written
+    DigitalUpdate(DigitalPort, BitUpdate),
+    /// Writes an emulated AVR register, see [AvrRegister]
+    SetMem8 {
+        address: u16,
+        value: u8,
+    },
+    GetMem8 {
+        address: u16,
+    },
+    /// Writes the registers at `address` and `address + 1` with the low and high byte
+    SetMem16 {
+        address: u16,
+        value: u16,
+    },
+    GetMem16 {
+        address: u16,
+    },
+}
+
+impl Request {
+    /// Instruction byte the request is sent with
+    pub fn instruction(&self) -> u8 {
+        match self {
+            Request::Discard => consts::RQ_DISCARD,
+            Request::InitTest(_) => consts::RQ_TEST,
+            Request::Info => consts::RQ_INFO,
+            Request::IntTest(_) => consts::RQ_INT_TEST,
+            Request::SelfTest => consts::RQ_SELF_TEST,
+            Request::ReadDipSwitch => consts::RQ_READ_DIP_SWITCH,
+            Request::AnalogWrite(AnalogWritePort::Port1, _) => consts::RQ_ANALOG_WRITE_0,
+            Request::AnalogWrite(AnalogWritePort::Port2, _) => consts::RQ_ANALOG_WRITE_1,
+            Request::AnalogRead(_) => consts::RQ_ANALOG_READ,
+            Request::AdcDacStroke { .. } => consts::RQ_ADC_DAC_STROKE,
+            Request::PwmSetFrequency(_) => consts::RQ_PWM_SET_FREQ,
+            Request::PwmSetValue(_) => consts::RQ_PWM_SET_VALUE,
+            Request::DigitalWrite(DigitalPort::Port1, _) => consts::RQ_DIGITAL_WRITE_0,
+            Request::DigitalWrite(DigitalPort::Port2, _) => consts::RQ_DIGITAL_WRITE_1,
+            Request::DigitalRead(DigitalPort::Port1) => consts::RQ_DIGITAL_READ_0,
+            Request::DigitalRead(DigitalPort::Port2) => consts::RQ_DIGITAL_READ_1,
+            Request::SetErrorMode(_) => consts::RQ_SET_ERROR_MODE,
+            Request::SetInterByteTimeout(_) => consts::RQ_SET_INTER_BYTE_TIMEOUT,
+            Request::Capabilities => consts::RQ_CAPABILITIES,
+            Request::SetAdcMode(_) => consts::RQ_SET_ADC_MODE,
+            Request::AnalogReadMillivolts(_) => consts::RQ_ANALOG_READ_MV,
+            Request::SetAttenuation(_, _) => consts::RQ_SET_ATTENUATION,
+            Request::FullScale(_) => consts::RQ_FULL_SCALE,
+            Request::AnalogReadStats { .. } => consts::RQ_ANALOG_READ_STATS,
+            Request::StreamStart { .. } => consts::RQ_STREAM_START,
+            Request::StreamStop => consts::RQ_STREAM_STOP,
+            Request::AnalogReadAll => consts::RQ_ANALOG_READ_ALL,
+            Request::SetDirection(DigitalPort::Port1, _) => consts::RQ_SET_DIRECTION_0,
+            Request::SetDirection(DigitalPort::Port2, _) => consts::RQ_SET_DIRECTION_1,
+            Request::SetPull {
+                port: DigitalPort::Port1,
+                ..
+            } => consts::RQ_SET_PULL_0,
+            Request::SetPull {
+                port: DigitalPort::Port2,
+                ..
+            } => consts::RQ_SET_PULL_1,
+            Request::DigitalUpdate(port, update) => update.instruction(*port),
+            Request::SetMem8 { .. } => consts::RQ_SET_MEM_8,
+            Request::GetMem8 { .. } => consts::RQ_GET_MEM_8,
+            Request::SetMem16 { .. } => consts::RQ_SET_MEM_16,
+            Request::GetMem16 { .. } => consts::RQ_GET_MEM_16,
+        }
+    }
+}
+
+/// A frame that was received completely but can not be executed
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct InvalidRequest {
+    pub instruction: u8,
+    pub code: ErrorCode,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AnalogWritePort {
+    Port1,
+    Port2,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum DigitalPort {
+    Port1,
+    Port2,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AnalogReadPort {
+    Port1 = 0,
+    Port2 = 1,
+    Port3 = 2,
+    Port4 = 3,
+    Port5 = 4,
+    Port6 = 5,
+    Port7 = 6,
+    Port8 = 7,
+}
+
+/// Change of the output levels of a [DigitalPort], bits as in [Request::DigitalWrite]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum BitUpdate {
+    /// Takes the bits of `value` selected by `mask`
+    Masked {
+        value: u8,
+        mask: u8,
+    },
+    Set(u8),
+    Clear(u8),
+    Toggle(u8),
+}
+
+impl BitUpdate {
+    /// Levels after the update of `levels`
+    pub fn apply(self, levels: u8) -> u8 {
+        match self {
+            BitUpdate::Masked { value, mask } => (levels & !mask) | (value & mask),
+            BitUpdate::Set(bits) => levels | bits,
+            BitUpdate::Clear(bits) => levels & !bits,
+            BitUpdate::Toggle(bits) => levels ^ bits,
+        }
+    }
+
+    fn instruction(self, port: DigitalPort) -> u8 {
+        match (self, port) {
+            (BitUpdate::Masked { .. }, DigitalPort::Port1) => consts::RQ_DIGITAL_WRITE_MASKED_0,
+            (BitUpdate::Masked { .. }, DigitalPort::Port2) => consts::RQ_DIGITAL_WRITE_MASKED_1,
+            (BitUpdate::Set(_), DigitalPort::Port1) => consts::RQ_DIGITAL_SET_BITS_0,
+            (BitUpdate::Set(_), DigitalPort::Port2) => consts::RQ_DIGITAL_SET_BITS_1,
+            (BitUpdate::Clear(_), DigitalPort::Port1) => consts::RQ_DIGITAL_CLEAR_BITS_0,
+            (BitUpdate::Clear(_), DigitalPort::Port2) => consts::RQ_DIGITAL_CLEAR_BITS_1,
+            (BitUpdate::Toggle(_), DigitalPort::Port1) => consts::RQ_DIGITAL_TOGGLE_BITS_0,
+            (BitUpdate::Toggle(_), DigitalPort::Port2) => consts::RQ_DIGITAL_TOGGLE_BITS_1,
+        }
+    }
+}
+
+/// I/O register of the B15's microcontroller emulated on a digital port
+///
+/// Like on the AVR a set `Ddr` bit makes a pin an output, `Port` holds the levels of outputs
+/// and enables the pull-ups of inputs, `Pin` reads the levels and toggles `Port` when written.
+/// `Ddr` starts at 0xFF, all outputs, rather than the AVR's 0x00.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum AvrRegister {
+    Pin(DigitalPort),
+    Ddr(DigitalPort),
+    Port(DigitalPort),
+}
+
+impl AvrRegister {
+    /// Side A stands in for the AVR's port A and side B for port B
+    pub fn from_address(address: u16) -> Option<Self> {
+        match address {
+            consts::REG_PINA => Some(AvrRegister::Pin(DigitalPort::Port1)),
+            consts::REG_DDRA => Some(AvrRegister::Ddr(DigitalPort::Port1)),
+            consts::REG_PORTA => Some(AvrRegister::Port(DigitalPort::Port1)),
+            consts::REG_PINB => Some(AvrRegister::Pin(DigitalPort::Port2)),
+            consts::REG_DDRB => Some(AvrRegister::Ddr(DigitalPort::Port2)),
+            consts::REG_PORTB => Some(AvrRegister::Port(DigitalPort::Port2)),
+            _ => None,
+        }
+    }
+
+    pub fn port(self) -> DigitalPort {
+        match self {
+            AvrRegister::Pin(port) | AvrRegister::Ddr(port) | AvrRegister::Port(port) => port,
+        }
+    }
+}
+
+/// Scale of [Response::AnalogValue](crate::Response::AnalogValue) and the stroke samples
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AdcMode {
+    /// Raw counts of the 12 bit ADC, the voltage range depends on the attenuation
+    #[default]
+    Native = 0,
+    /// 10 bit values referenced to 5 V like the B15, computed from calibrated millivolts.
+    /// Readings fail with [ErrorCode::Unsupported] on chips without calibration data.
+    B15 = 1,
+}
+
+impl AdcMode {
+    pub fn from_u8(mode: u8) -> Option<Self> {
+        match mode {
+            0 => Some(AdcMode::Native),
+            1 => Some(AdcMode::B15),
+            _ => None,
+        }
+    }
+}
+
+/// Input attenuation of an analog read port, more attenuation extends the measurable range
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Attenuation {
+    #[default]
+    Db0 = 0,
+    Db2_5 = 1,
+    Db6 = 2,
+    Db11 = 3,
+}
+
+impl Attenuation {
+    pub fn from_u8(attenuation: u8) -> Option<Self> {
+        match attenuation {
+            0 => Some(Attenuation::Db0),
+            1 => Some(Attenuation::Db2_5),
+            2 => Some(Attenuation::Db6),
+            3 => Some(Attenuation::Db11),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes the next request sent by the host
+///
+/// Unknown instructions and payloads outside of the accepted range are consumed and
+/// returned as [InvalidRequest]. Errors after the instruction byte carry it as [Instruction].
+pub async fn read_request<R: Read>(
+    reader: &mut R,
+) -> error_stack::Result<Result<Request, InvalidRequest>, CommunicationError> {
+    let [instruction] = read_array(reader)
+        .await
+        .map_err(|report| report.attach_printable(Instruction(consts::NO_INSTRUCTION)))?;
+    info!("Got Instruction: {instruction:#x}");
+    read_payload(reader, instruction)
+        .await
+        .map_err(|report| report.attach_printable(Instruction(instruction)))
+}
+
+/// Like [read_request], but fails with [CommunicationError::Timeout] when the payload stalls
+///
+/// Waiting for the instruction byte is unbounded, afterwards each byte has to arrive within
+/// `timeout_ms` of the previous one. This keeps a host that died mid-frame from having the
+/// first bytes of the next session interpreted as the rest of the abandoned frame.
+/// A `timeout_ms` of 0 waits forever like [read_request].
+pub async fn read_request_with_timeout<R: Read, D: DelayNs>(
+    reader: &mut R,
+    delay: &mut D,
+    timeout_ms: u32,
+) -> error_stack::Result<Result<Request, InvalidRequest>, CommunicationError> {
+    let [instruction] = read_array(reader)
+        .await
+        .map_err(|report| report.attach_printable(Instruction(consts::NO_INSTRUCTION)))?;
+    info!("Got Instruction: {instruction:#x}");
+    let mut reader = InterByteTimeout {
+        reader,
+        delay,
+        timeout_ms,
+    };
+    read_payload(&mut reader, instruction)
+        .await
+        .map_err(|report| report.attach_printable(Instruction(instruction)))
+}
+
+async fn read_payload<R: Read>(
+    reader: &mut R,
+    instruction: u8,
+) -> error_stack::Result<Result<Request, InvalidRequest>, CommunicationError> {
+    let invalid_argument = InvalidRequest {
+        instruction,
+        code: ErrorCode::InvalidArgument,
+    };
+    match instruction {
+        consts::RQ_DISCARD => Ok(Ok(Request::Discard)),
+        consts::RQ_TEST => {
+            let [test] = read_array(reader).await?;
+            Ok(Ok(Request::InitTest(test)))
+        }
+        consts::RQ_INFO => Ok(Ok(Request::Info)),
+        consts::RQ_INT_TEST => {
+            let buffer = read_array(reader).await?;
+            Ok(Ok(Request::IntTest(u16::from_le_bytes(buffer))))
+        }
+        consts::RQ_SELF_TEST => Ok(Ok(Request::SelfTest)),
+        consts::RQ_READ_DIP_SWITCH => Ok(Ok(Request::ReadDipSwitch)),
+        consts::RQ_ANALOG_WRITE_0 | consts::RQ_ANALOG_WRITE_1 => {
+            let port = if instruction == consts::RQ_ANALOG_WRITE_0 {
+                AnalogWritePort::Port1
+            } else {
+                AnalogWritePort::Port2
+            };
+            let [value_low, value_high] = read_array(reader).await?;
+            let value = (value_high as u16) << 8 | value_low as u16;
+            Ok(Ok(Request::AnalogWrite(port, value)))
+        }
+        consts::RQ_ANALOG_READ => {
+            let [port] = read_array(reader).await?;
+
+            let Some(port) = analog_read_port(port) else {
+                return Ok(Err(invalid_argument));
+            };
+            Ok(Ok(Request::AnalogRead(port)))
+        }
+        consts::RQ_ADC_DAC_STROKE => {
+            let [write_port, read_port, steps_low, steps_high, settle_low, settle_high] =
+                read_array(reader).await?;
+
+            let write_port = match write_port {
+                0 => AnalogWritePort::Port1,
+                1 => AnalogWritePort::Port2,
+                _ => return Ok(Err(invalid_argument)),
+            };
+            let Some(read_port) = analog_read_port(read_port) else {
+                return Ok(Err(invalid_argument));
+            };
+            let steps = u16::from_le_bytes([steps_low, steps_high]);
+            let settle_time_us = u16::from_le_bytes([settle_low, settle_high]);
+            if steps == 0
+                || steps > consts::MAX_STROKE_STEPS
+                || u32::from(steps) * u32::from(settle_time_us) > consts::MAX_STROKE_DURATION_US
+            {
+                return Ok(Err(invalid_argument));
+            }
+            Ok(Ok(Request::AdcDacStroke {
+                write_port,
+                read_port,
+                steps,
+                settle_time_us,
+            }))
+        }
+        consts::RQ_PWM_SET_FREQ => {
+            let frequency = u32::from_le_bytes(read_array(reader).await?);
+            if frequency != 0
+                && !(consts::PWM_MIN_FREQUENCY..=consts::PWM_MAX_FREQUENCY).contains(&frequency)
+            {
+                return Ok(Err(invalid_argument));
+            }
+            Ok(Ok(Request::PwmSetFrequency(frequency)))
+        }
+        consts::RQ_PWM_SET_VALUE => {
+            let buffer = read_array(reader).await?;
+            Ok(Ok(Request::PwmSetValue(u16::from_le_bytes(buffer))))
+        }
+
+        consts::RQ_DIGITAL_WRITE_0 | consts::RQ_DIGITAL_WRITE_1 => {
+            let port = if instruction == consts::RQ_DIGITAL_WRITE_0 {
+                DigitalPort::Port1
+            } else {
+                DigitalPort::Port2
+            };
+            let [value] = read_array(reader).await?;
+            Ok(Ok(Request::DigitalWrite(port, value)))
+        }
+        consts::RQ_DIGITAL_READ_0 | consts::RQ_DIGITAL_READ_1 => {
+            let port = if instruction == consts::RQ_DIGITAL_READ_0 {
+                DigitalPort::Port1
+            } else {
+                DigitalPort::Port2
+            };
+            Ok(Ok(Request::DigitalRead(port)))
+        }
+
+        consts::RQ_SET_ERROR_MODE => {
+            let [mode] = read_array(reader).await?;
+            let mode = match mode {
+                0 => ErrorMode::Legacy,
+                1 => ErrorMode::Extended,
+                _ => return Ok(Err(invalid_argument)),
+            };
+            Ok(Ok(Request::SetErrorMode(mode)))
+        }
+        consts::RQ_SET_INTER_BYTE_TIMEOUT => Ok(Ok(Request::SetInterByteTimeout(
+            u16::from_le_bytes(read_array(reader).await?),
+        ))),
+        consts::RQ_CAPABILITIES => Ok(Ok(Request::Capabilities)),
+        consts::RQ_SET_ADC_MODE => {
+            let [mode] = read_array(reader).await?;
+            let Some(mode) = AdcMode::from_u8(mode) else {
+                return Ok(Err(invalid_argument));
+            };
+            Ok(Ok(Request::SetAdcMode(mode)))
+        }
+        consts::RQ_ANALOG_READ_MV => {
+            let [port] = read_array(reader).await?;
+            let Some(port) = analog_read_port(port) else {
+                return Ok(Err(invalid_argument));
+            };
+            Ok(Ok(Request::AnalogReadMillivolts(port)))
+        }
+        consts::RQ_SET_ATTENUATION => {
+            let [port, attenuation] = read_array(reader).await?;
+            let (Some(port), Some(attenuation)) =
+                (analog_read_port(port), Attenuation::from_u8(attenuation))
+            else {
+                return Ok(Err(invalid_argument));
+            };
+            Ok(Ok(Request::SetAttenuation(port, attenuation)))
+        }
+        consts::RQ_FULL_SCALE => {
+            let [port] = read_array(reader).await?;
+            let Some(port) = analog_read_port(port) else {
+                return Ok(Err(invalid_argument));
+            };
+            Ok(Ok(Request::FullScale(port)))
+        }
+        consts::RQ_ANALOG_READ_STATS => {
+            let [port, samples_low, samples_high, interval_low, interval_high] =
+                read_array(reader).await?;
+            let Some(port) = analog_read_port(port) else {
+                return Ok(Err(invalid_argument));
+            };
+            let samples = u16::from_le_bytes([samples_low, samples_high]);
+            let interval_us = u16::from_le_bytes([interval_low, interval_high]);
+            if samples == 0
+                || u32::from(samples) * u32::from(interval_us) > consts::MAX_STATS_DURATION_US
+            {
+                return Ok(Err(invalid_argument));
+            }
+            Ok(Ok(Request::AnalogReadStats {
+                port,
+                samples,
+                interval_us,
+            }))
+        }
+        consts::RQ_STREAM_START => {
+            let [ports, r0, r1, r2, r3] = read_array(reader).await?;
+            let sample_rate = u32::from_le_bytes([r0, r1, r2, r3]);
+            if ports == 0
+                || !(consts::STREAM_MIN_SAMPLE_RATE..=consts::STREAM_MAX_SAMPLE_RATE)
+                    .contains(&sample_rate)
+            {
+                return Ok(Err(invalid_argument));
+            }
+            Ok(Ok(Request::StreamStart { ports, sample_rate }))
+        }
+        consts::RQ_STREAM_STOP => Ok(Ok(Request::StreamStop)),
+        consts::RQ_ANALOG_READ_ALL => Ok(Ok(Request::AnalogReadAll)),
+        consts::RQ_SET_DIRECTION_0 | consts::RQ_SET_DIRECTION_1 => {
+            let port = if instruction == consts::RQ_SET_DIRECTION_0 {
+                DigitalPort::Port1
+            } else {
+                DigitalPort::Port2
+            };
+            let [outputs] = read_array(reader).await?;
+            Ok(Ok(Request::SetDirection(port, outputs)))
+        }
+        consts::RQ_DIGITAL_WRITE_MASKED_0 | consts::RQ_DIGITAL_WRITE_MASKED_1 => {
+            let port = if instruction == consts::RQ_DIGITAL_WRITE_MASKED_0 {
+                DigitalPort::Port1
+            } else {
+                DigitalPort::Port2
+            };
+            let [value, mask] = read_array(reader).await?;
+            Ok(Ok(Request::DigitalUpdate(
+                port,
+                BitUpdate::Masked { value, mask },
+            )))
+        }
+        consts::RQ_DIGITAL_SET_BITS_0
+        | consts::RQ_DIGITAL_SET_BITS_1
+        | consts::RQ_DIGITAL_CLEAR_BITS_0
+        | consts::RQ_DIGITAL_CLEAR_BITS_1
+        | consts::RQ_DIGITAL_TOGGLE_BITS_0
+        | consts::RQ_DIGITAL_TOGGLE_BITS_1 => {
+            let port = match instruction {
+                consts::RQ_DIGITAL_SET_BITS_0
+                | consts::RQ_DIGITAL_CLEAR_BITS_0
+                | consts::RQ_DIGITAL_TOGGLE_BITS_0 => DigitalPort::Port1,
+                _ => DigitalPort::Port2,
+            };
+            let [bits] = read_array(reader).await?;
+            let update = match instruction {
+                consts::RQ_DIGITAL_SET_BITS_0 | consts::RQ_DIGITAL_SET_BITS_1 => {
+                    BitUpdate::Set(bits)
+                }
+                consts::RQ_DIGITAL_CLEAR_BITS_0 | consts::RQ_DIGITAL_CLEAR_BITS_1 => {
+                    BitUpdate::Clear(bits)
+                }
+                _ => BitUpdate::Toggle(bits),
+            };
+            Ok(Ok(Request::DigitalUpdate(port, update)))
+        }
+        consts::RQ_SET_MEM_8 => {
+            let [address_low, address_high, value] = read_array(reader).await?;
+            Ok(Ok(Request::SetMem8 {
+                address: u16::from_le_bytes([address_low, address_high]),
+                value,
+            }))
+        }
+        consts::RQ_GET_MEM_8 => {
+            let address = u16::from_le_bytes(read_array(reader).await?);
+            Ok(Ok(Request::GetMem8 { address }))
+        }
+        consts::RQ_SET_MEM_16 => {
+            let [address_low, address_high, value_low, value_high] = read_array(reader).await?;
+            Ok(Ok(Request::SetMem16 {
+                address: u16::from_le_bytes([address_low, address_high]),
+                value: u16::from_le_bytes([value_low, value_high]),
+            }))
+        }
+        consts::RQ_GET_MEM_16 => {
+            let address = u16::from_le_bytes(read_array(reader).await?);
+            Ok(Ok(Request::GetMem16 { address }))
+        }
+        consts::RQ_SET_PULL_0 | consts::RQ_SET_PULL_1 => {
+            let port = if instruction == consts::RQ_SET_PULL_0 {
+                DigitalPort::Port1
+            } else {
+                DigitalPort::Port2
+            };
+            let [pull_up, pull_down] = read_array(reader).await?;
+            Ok(Ok(Request::SetPull {
+                port,
+                pull_up,
+                pull_down,
+            }))
+        }
+
+        instruction => {
+            warn!("Received unknown instruction: {instruction:#x}");
+            Ok(Err(InvalidRequest {
+                instruction,
+                code: ErrorCode::UnknownInstruction,
+            }))
+        }
+    }
+}
+pub(crate) fn analog_read_port(port: u8) -> Option<AnalogReadPort> {
+    match port {
+        0 => Some(AnalogReadPort::Port1),
+        1 => Some(AnalogReadPort::Port2),
+        2 => Some(AnalogReadPort::Port3),
+        3 => Some(AnalogReadPort::Port4),
+        4 => Some(AnalogReadPort::Port5),
+        5 => Some(AnalogReadPort::Port6),
+        6 => Some(AnalogReadPort::Port7),
+        7 => Some(AnalogReadPort::Port8),
+        _ => None,
+    }
+}
+
+/// Encodes a request on the host side, the counterpart of [read_request]
+pub async fn write_request<W: Write>(
+    writer: &mut W,
+    request: &Request,
+) -> error_stack::Result<(), CommunicationError> {
+    match *request {
+        Request::Discard => write_all(writer, &[consts::RQ_DISCARD]).await?,
+        Request::InitTest(value) => write_all(writer, &[consts::RQ_TEST, value]).await?,
+        Request::Info => write_all(writer, &[consts::RQ_INFO]).await?,
+        Request::IntTest(value) => {
+            let [low, high] = value.to_le_bytes();
+            write_all(writer, &[consts::RQ_INT_TEST, low, high]).await?
+        }
+        Request::SelfTest => write_all(writer, &[consts::RQ_SELF_TEST]).await?,
+        Request::ReadDipSwitch => write_all(writer, &[consts::RQ_READ_DIP_SWITCH]).await?,
+        Request::AnalogWrite(port, value) => {
+            let instruction = match port {
+                AnalogWritePort::Port1 => consts::RQ_ANALOG_WRITE_0,
+                AnalogWritePort::Port2 => consts::RQ_ANALOG_WRITE_1,
+            };
+            let [low, high] = value.to_le_bytes();
+            write_all(writer, &[instruction, low, high]).await?
+        }
+        Request::AnalogRead(port) => {
+            write_all(writer, &[consts::RQ_ANALOG_READ, port as u8]).await?
+        }
+        Request::AdcDacStroke {
+            write_port,
+            read_port,
+            steps,
+            settle_time_us,
+        } => {
+            let [steps_low, steps_high] = steps.to_le_bytes();
+            let [settle_low, settle_high] = settle_time_us.to_le_bytes();
+            write_all(
+                writer,
+                &[
+                    consts::RQ_ADC_DAC_STROKE,
+                    write_port as u8,
+                    read_port as u8,
+                    steps_low,
+                    steps_high,
+                    settle_low,
+                    settle_high,
+                ],
+            )
+            .await?
+        }
+        Request::PwmSetFrequency(frequency) => {
+            let [f0, f1, f2, f3] = frequency.to_le_bytes();
+            write_all(writer, &[consts::RQ_PWM_SET_FREQ, f0, f1, f2, f3]).await?
+        }
+        Request::PwmSetValue(value) => {
+            let [low, high] = value.to_le_bytes();
+            write_all(writer, &[consts::RQ_PWM_SET_VALUE, low, high]).await?
+        }
+        Request::DigitalWrite(port, value) => {
+            let instruction = match port {
+                DigitalPort::Port1 => consts::RQ_DIGITAL_WRITE_0,
+                DigitalPort::Port2 => consts::RQ_DIGITAL_WRITE_1,
+            };
+            write_all(writer, &[instruction, value]).await?
+        }
+        Request::DigitalRead(port) => {
+            let instruction = match port {
+                DigitalPort::Port1 => consts::RQ_DIGITAL_READ_0,
+                DigitalPort::Port2 => consts::RQ_DIGITAL_READ_1,
+            };
+            write_all(writer, &[instruction]).await?
+        }
+        Request::SetErrorMode(mode) => {
+            write_all(writer, &[consts::RQ_SET_ERROR_MODE, mode as u8]).await?
+        }
+        Request::SetInterByteTimeout(timeout_ms) => {
+            let [low, high] = timeout_ms.to_le_bytes();
+            write_all(writer, &[consts::RQ_SET_INTER_BYTE_TIMEOUT, low, high]).await?
+        }
+        Request::Capabilities => write_all(writer, &[consts::RQ_CAPABILITIES]).await?,
+        Request::SetAdcMode(mode) => {
+            write_all(writer, &[consts::RQ_SET_ADC_MODE, mode as u8]).await?
+        }
+        Request::AnalogReadMillivolts(port) => {
+            write_all(writer, &[consts::RQ_ANALOG_READ_MV, port as u8]).await?
+        }
+        Request::SetAttenuation(port, attenuation) => {
+            write_all(
+                writer,
+                &[consts::RQ_SET_ATTENUATION, port as u8, attenuation as u8],
+            )
+            .await?
+        }
+        Request::FullScale(port) => write_all(writer, &[consts::RQ_FULL_SCALE, port as u8]).await?,
+        Request::AnalogReadStats {
+            port,
+            samples,
+            interval_us,
+        } => {
+            let [samples_low, samples_high] = samples.to_le_bytes();
+            let [interval_low, interval_high] = interval_us.to_le_bytes();
+            write_all(
+                writer,
+                &[
+                    consts::RQ_ANALOG_READ_STATS,
+                    port as u8,
+                    samples_low,
+                    samples_high,
+                    interval_low,
+                    interval_high,
+                ],
+            )
+            .await?
+        }
+        Request::StreamStart { ports, sample_rate } => {
+            let [r0, r1, r2, r3] = sample_rate.to_le_bytes();
+            write_all(writer, &[consts::RQ_STREAM_START, ports, r0, r1, r2, r3]).await?
+        }
+        Request::StreamStop => write_all(writer, &[consts::RQ_STREAM_STOP]).await?,
+        Request::AnalogReadAll => write_all(writer, &[consts::RQ_ANALOG_READ_ALL]).await?,
+        Request::SetDirection(port, outputs) => {
+            let instruction = match port {
+                DigitalPort::Port1 => consts::RQ_SET_DIRECTION_0,
+                DigitalPort::Port2 => consts::RQ_SET_DIRECTION_1,
+            };
+            write_all(writer, &[instruction, outputs]).await?
+        }
+        Request::SetPull {
+            port,
+            pull_up,
+            pull_down,
+        } => {
+            let instruction = match port {
+                DigitalPort::Port1 => consts::RQ_SET_PULL_0,
+                DigitalPort::Port2 => consts::RQ_SET_PULL_1,
+            };
+            write_all(writer, &[instruction, pull_up, pull_down]).await?
+        }
+        Request::DigitalUpdate(port, update) => {
+            let instruction = update.instruction(port);
+            match update {
+                BitUpdate::Masked { value, mask } => {
+                    write_all(writer, &[instruction, value, mask]).await?
+                }
+                BitUpdate::Set(bits) | BitUpdate::Clear(bits) | BitUpdate::Toggle(bits) => {
+                    write_all(writer, &[instruction, bits]).await?
+                }
+            }
+        }
+        Request::SetMem8 { address, value } => {
+            let [address_low, address_high] = address.to_le_bytes();
+            write_all(
+                writer,
+                &[consts::RQ_SET_MEM_8, address_low, address_high, value],
+            )
+            .await?
+        }
+        Request::GetMem8 { address } => {
+            let [address_low, address_high] = address.to_le_bytes();
+            write_all(writer, &[consts::RQ_GET_MEM_8, address_low, address_high]).await?
+        }
+        Request::SetMem16 { address, value } => {
+            let [address_low, address_high] = address.to_le_bytes();
+            let [value_low, value_high] = value.to_le_bytes();
+            write_all(
+                writer,
+                &[
+                    consts::RQ_SET_MEM_16,
+                    address_low,
+                    address_high,
+                    value_low,
+                    value_high,
+                ],
+            )
+            .await?
+        }
+        Request::GetMem16 { address } => {
+            let [address_low, address_high] = address.to_le_bytes();
+            write_all(writer, &[consts::RQ_GET_MEM_16, address_low, address_high]).await?
+        }
+    }
+    flush(writer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+
+    fn decode(bytes: &[u8]) -> Option<Request> {
+        reject_or_decode(bytes).ok()
+    }
+
+    fn reject_or_decode(bytes: &[u8]) -> Result<Request, InvalidRequest> {
+        let mut reader = bytes;
+        let request = block_on(read_request(&mut reader)).expect("decoding failed");
+        assert!(reader.is_empty(), "frame was not consumed completely");
+        request
+    }
+
+    #[test]
+    fn decode_requests_without_payload() {
+        assert_eq!(decode(&[consts::RQ_DISCARD]), Some(Request::Discard));
+        assert_eq!(decode(&[consts::RQ_INFO]), Some(Request::Info));
+        assert_eq!(decode(&[consts::RQ_SELF_TEST]), Some(Request::SelfTest));
+        assert_eq!(
+            decode(&[consts::RQ_CAPABILITIES]),
+            Some(Request::Capabilities)
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ_ALL]),
+            Some(Request::AnalogReadAll)
+        );
+        assert_eq!(
+            decode(&[consts::RQ_READ_DIP_SWITCH]),
+            Some(Request::ReadDipSwitch)
+        );
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_READ_0]),
+            Some(Request::DigitalRead(DigitalPort::Port1))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_READ_1]),
+            Some(Request::DigitalRead(DigitalPort::Port2))
+        );
+    }
+
+    #[test]
+    fn decode_test_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_TEST, 0x5A]),
+            Some(Request::InitTest(0x5A))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_INT_TEST, 0x34, 0x12]),
+            Some(Request::IntTest(0x1234))
+        );
+    }
+
+    #[test]
+    fn decode_analog_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_WRITE_0, 0xFF, 0x03]),
+            Some(Request::AnalogWrite(AnalogWritePort::Port1, 1023))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_WRITE_1, 0x01, 0x00]),
+            Some(Request::AnalogWrite(AnalogWritePort::Port2, 1))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ, 0]),
+            Some(Request::AnalogRead(AnalogReadPort::Port1))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ, 7]),
+            Some(Request::AnalogRead(AnalogReadPort::Port8))
+        );
+        assert_eq!(decode(&[consts::RQ_ANALOG_READ, 8]), None);
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ_MV, 2]),
+            Some(Request::AnalogReadMillivolts(AnalogReadPort::Port3))
+        );
+        assert_eq!(decode(&[consts::RQ_ANALOG_READ_MV, 8]), None);
+    }
+
+    #[test]
+    fn decode_stats_request() {
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ_STATS, 3, 0x00, 0x01, 0x0A, 0x00]),
+            Some(Request::AnalogReadStats {
+                port: AnalogReadPort::Port4,
+                samples: 256,
+                interval_us: 10,
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ_STATS, 3, 0x00, 0x00, 0x00, 0x00]),
+            None
+        );
+        // 1000 samples 1 ms apart take exactly the maximum duration
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ_STATS, 0, 0xE8, 0x03, 0xE8, 0x03]),
+            Some(Request::AnalogReadStats {
+                port: AnalogReadPort::Port1,
+                samples: 1000,
+                interval_us: 1000,
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ_STATS, 0, 0xE9, 0x03, 0xE8, 0x03]),
+            None
+        );
+        // Back to back sampling is not limited by the interval
+        assert_eq!(
+            decode(&[consts::RQ_ANALOG_READ_STATS, 0, 0xFF, 0xFF, 0x00, 0x00]),
+            Some(Request::AnalogReadStats {
+                port: AnalogReadPort::Port1,
+                samples: u16::MAX,
+                interval_us: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_stream_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_STREAM_START, 0b101, 0x10, 0x27, 0x00, 0x00]),
+            Some(Request::StreamStart {
+                ports: 0b101,
+                sample_rate: 10_000,
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_STREAM_START, 0, 0x10, 0x27, 0x00, 0x00]),
+            None
+        );
+        assert_eq!(
+            decode(&[consts::RQ_STREAM_START, 1, 0x64, 0x00, 0x00, 0x00]),
+            None
+        );
+        assert_eq!(decode(&[consts::RQ_STREAM_STOP]), Some(Request::StreamStop));
+    }
+
+    #[test]
+    fn decode_attenuation_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_SET_ATTENUATION, 5, 3]),
+            Some(Request::SetAttenuation(
+                AnalogReadPort::Port6,
+                Attenuation::Db11
+            ))
+        );
+        assert_eq!(decode(&[consts::RQ_SET_ATTENUATION, 0, 4]), None);
+        assert_eq!(decode(&[consts::RQ_SET_ATTENUATION, 8, 0]), None);
+        assert_eq!(
+            decode(&[consts::RQ_FULL_SCALE, 1]),
+            Some(Request::FullScale(AnalogReadPort::Port2))
+        );
+    }
+
+    #[test]
+    fn decode_stroke_request() {
+        assert_eq!(
+            decode(&[consts::RQ_ADC_DAC_STROKE, 1, 5, 0x00, 0x01, 0xE8, 0x03]),
+            Some(Request::AdcDacStroke {
+                write_port: AnalogWritePort::Port2,
+                read_port: AnalogReadPort::Port6,
+                steps: 256,
+                settle_time_us: 1000,
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ADC_DAC_STROKE, 2, 0, 0x10, 0x00, 0, 0]),
+            None
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ADC_DAC_STROKE, 0, 0, 0x00, 0x00, 0, 0]),
+            None
+        );
+        let [steps_low, steps_high] = (consts::MAX_STROKE_STEPS + 1).to_le_bytes();
+        assert_eq!(
+            decode(&[consts::RQ_ADC_DAC_STROKE, 0, 0, steps_low, steps_high, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn decode_limits_stroke_duration() {
+        // 1000 steps of 1 ms take exactly the limit
+        assert_eq!(
+            decode(&[consts::RQ_ADC_DAC_STROKE, 0, 0, 0xE8, 0x03, 0xE8, 0x03]),
+            Some(Request::AdcDacStroke {
+                write_port: AnalogWritePort::Port1,
+                read_port: AnalogReadPort::Port1,
+                steps: 1000,
+                settle_time_us: 1000,
+            })
+        );
+        assert_eq!(
+            reject_or_decode(&[consts::RQ_ADC_DAC_STROKE, 0, 0, 0xE8, 0x03, 0xE9, 0x03]),
+            Err(InvalidRequest {
+                instruction: consts::RQ_ADC_DAC_STROKE,
+                code: ErrorCode::InvalidArgument
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_ADC_DAC_STROKE, 0, 0, 0x01, 0x00, 0xFF, 0xFF]),
+            Some(Request::AdcDacStroke {
+                write_port: AnalogWritePort::Port1,
+                read_port: AnalogReadPort::Port1,
+                steps: 1,
+                settle_time_us: u16::MAX,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_pwm_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_PWM_SET_FREQ, 0x10, 0x27, 0x00, 0x00]),
+            Some(Request::PwmSetFrequency(10_000))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_PWM_SET_FREQ, 0, 0, 0, 0]),
+            Some(Request::PwmSetFrequency(0))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_PWM_SET_FREQ, 0xFF, 0xFF, 0xFF, 0xFF]),
+            None
+        );
+        let [f0, f1, f2, f3] = consts::PWM_MAX_FREQUENCY.to_le_bytes();
+        assert_eq!(
+            decode(&[consts::RQ_PWM_SET_FREQ, f0, f1, f2, f3]),
+            Some(Request::PwmSetFrequency(consts::PWM_MAX_FREQUENCY))
+        );
+        let [f0, f1, f2, f3] = (consts::PWM_MAX_FREQUENCY + 1).to_le_bytes();
+        assert_eq!(decode(&[consts::RQ_PWM_SET_FREQ, f0, f1, f2, f3]), None);
+        assert_eq!(
+            decode(&[consts::RQ_PWM_SET_VALUE, 0x00, 0x02]),
+            Some(Request::PwmSetValue(512))
+        );
+    }
+
+    #[test]
+    fn decode_digital_write_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_WRITE_0, 0xA5]),
+            Some(Request::DigitalWrite(DigitalPort::Port1, 0xA5))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_WRITE_1, 0x5A]),
+            Some(Request::DigitalWrite(DigitalPort::Port2, 0x5A))
+        );
+    }
+
+    #[test]
+    fn decode_direction_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_SET_DIRECTION_0, 0x0F]),
+            Some(Request::SetDirection(DigitalPort::Port1, 0x0F))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_SET_DIRECTION_1, 0x00]),
+            Some(Request::SetDirection(DigitalPort::Port2, 0x00))
+        );
+    }
+
+    #[test]
+    fn decode_bit_update_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_WRITE_MASKED_1, 0x0A, 0x0F]),
+            Some(Request::DigitalUpdate(
+                DigitalPort::Port2,
+                BitUpdate::Masked {
+                    value: 0x0A,
+                    mask: 0x0F,
+                }
+            ))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_SET_BITS_0, 0x01]),
+            Some(Request::DigitalUpdate(
+                DigitalPort::Port1,
+                BitUpdate::Set(0x01)
+            ))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_CLEAR_BITS_1, 0x80]),
+            Some(Request::DigitalUpdate(
+                DigitalPort::Port2,
+                BitUpdate::Clear(0x80)
+            ))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_DIGITAL_TOGGLE_BITS_0, 0x41]),
+            Some(Request::DigitalUpdate(
+                DigitalPort::Port1,
+                BitUpdate::Toggle(0x41)
+            ))
+        );
+    }
+
+    #[test]
+    fn apply_bit_updates() {
+        let levels = 0b1010_0101;
+        assert_eq!(
+            BitUpdate::Masked {
+                value: 0b0101_1111,
+                mask: 0b1111_0000,
+            }
+            .apply(levels),
+            0b0101_0101
+        );
+        assert_eq!(BitUpdate::Set(0b0000_0011).apply(levels), 0b1010_0111);
+        assert_eq!(BitUpdate::Clear(0b1000_0001).apply(levels), 0b0010_0100);
+        assert_eq!(BitUpdate::Toggle(0b1111_0000).apply(levels), 0b0101_0101);
+    }
+
+    #[test]
+    fn decode_memory_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_SET_MEM_8, 0x22, 0x00, 0x5A]),
+            Some(Request::SetMem8 {
+                address: consts::REG_PORTA,
+                value: 0x5A,
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_GET_MEM_8, 0x23, 0x00]),
+            Some(Request::GetMem8 {
+                address: consts::REG_PINB,
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_SET_MEM_16, 0x21, 0x00, 0xFF, 0x0F]),
+            Some(Request::SetMem16 {
+                address: consts::REG_DDRA,
+                value: 0x0FFF,
+            })
+        );
+        assert_eq!(
+            decode(&[consts::RQ_GET_MEM_16, 0x24, 0x00]),
+            Some(Request::GetMem16 {
+                address: consts::REG_DDRB,
+            })
+        );
+    }
+
+    #[test]
+    fn avr_register_addresses() {
+        assert_eq!(
+            AvrRegister::from_address(consts::REG_DDRA),
+            Some(AvrRegister::Ddr(DigitalPort::Port1))
+        );
+        assert_eq!(
+            AvrRegister::from_address(consts::REG_PINB),
+            Some(AvrRegister::Pin(DigitalPort::Port2))
+        );
+        assert_eq!(
+            AvrRegister::from_address(consts::REG_PORTB),
+            Some(AvrRegister::Port(DigitalPort::Port2))
+        );
+        // PINC
+        assert_eq!(AvrRegister::from_address(0x26), None);
+    }
+
+    #[test]
+    fn decode_pull_requests() {
+        assert_eq!(
+            decode(&[consts::RQ_SET_PULL_1, 0xF0, 0x0F]),
+            Some(Request::SetPull {
+                port: DigitalPort::Port2,
+                pull_up: 0xF0,
+                pull_down: 0x0F,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_unknown_instruction() {
+        assert_eq!(decode(&[0xEE]), None);
+    }
+
+    #[test]
+    fn decode_error_mode_request() {
+        assert_eq!(
+            decode(&[consts::RQ_SET_ERROR_MODE, 1]),
+            Some(Request::SetErrorMode(ErrorMode::Extended))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_SET_ERROR_MODE, 0]),
+            Some(Request::SetErrorMode(ErrorMode::Legacy))
+        );
+    }
+
+    #[test]
+    fn decode_inter_byte_timeout_request() {
+        assert_eq!(
+            decode(&[consts::RQ_SET_INTER_BYTE_TIMEOUT, 0xF4, 0x01]),
+            Some(Request::SetInterByteTimeout(500))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_SET_INTER_BYTE_TIMEOUT, 0, 0]),
+            Some(Request::SetInterByteTimeout(0))
+        );
+    }
+
+    #[test]
+    fn decode_adc_mode_request() {
+        assert_eq!(
+            decode(&[consts::RQ_SET_ADC_MODE, 1]),
+            Some(Request::SetAdcMode(AdcMode::B15))
+        );
+        assert_eq!(
+            decode(&[consts::RQ_SET_ADC_MODE, 0]),
+            Some(Request::SetAdcMode(AdcMode::Native))
+        );
+        assert_eq!(decode(&[consts::RQ_SET_ADC_MODE, 2]), None);
+    }
+
+    #[test]
+    fn rejections_carry_code_and_instruction() {
+        assert_eq!(
+            reject_or_decode(&[0xEE]),
+            Err(InvalidRequest {
+                instruction: 0xEE,
+                code: ErrorCode::UnknownInstruction
+            })
+        );
+        assert_eq!(
+            reject_or_decode(&[consts::RQ_ANALOG_READ, 8]),
+            Err(InvalidRequest {
+                instruction: consts::RQ_ANALOG_READ,
+                code: ErrorCode::InvalidArgument
+            })
+        );
+        assert_eq!(
+            reject_or_decode(&[consts::RQ_SET_ERROR_MODE, 2]),
+            Err(InvalidRequest {
+                instruction: consts::RQ_SET_ERROR_MODE,
+                code: ErrorCode::InvalidArgument
+            })
+        );
+    }
+
+    #[test]
+    fn instruction_matches_encoding() {
+        let requests = [
+            Request::AnalogWrite(AnalogWritePort::Port2, 0),
+            Request::DigitalRead(DigitalPort::Port1),
+            Request::SetErrorMode(ErrorMode::Extended),
+            Request::SetInterByteTimeout(100),
+        ];
+        for request in requests {
+            let mut bytes = alloc::vec::Vec::new();
+            block_on(write_request(&mut bytes, &request)).expect("encoding failed");
+            assert_eq!(bytes[0], request.instruction());
+        }
+    }
+
+    #[test]
+    fn decode_truncated_frame() {
+        let mut reader: &[u8] = &[consts::RQ_ANALOG_WRITE_0, 0x01];
+        let report = block_on(read_request(&mut reader)).expect_err("truncated frame was accepted");
+        assert_eq!(
+            report.downcast_ref::<Instruction>(),
+            Some(&Instruction(consts::RQ_ANALOG_WRITE_0))
+        );
+    }
+
+    /// Hands out its bytes one at a time and then never completes, like a host that died
+    struct StallingReader<'a>(&'a [u8]);
+
+    impl embedded_io_async::ErrorType for StallingReader<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for StallingReader<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let Some((&byte, rest)) = self.0.split_first() else {
+                return core::future::pending().await;
+            };
+            buf[0] = byte;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    /// Yields to the executor before handing out each byte, so a deadline polled after the
+    /// read gets the chance to pass first
+    struct YieldingReader<'a>(&'a [u8]);
+
+    impl embedded_io_async::ErrorType for YieldingReader<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for YieldingReader<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            embassy_futures::yield_now().await;
+            let Some((&byte, rest)) = self.0.split_first() else {
+                return core::future::pending().await;
+            };
+            buf[0] = byte;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    /// Deadline that has always passed already
+    struct Expired;
+
+    impl DelayNs for Expired {
+        async fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    /// Deadline that is never reached
+    struct Never;
+
+    impl DelayNs for Never {
+        async fn delay_ns(&mut self, _ns: u32) {
+            core::future::pending().await
+        }
+    }
+
+    #[test]
+    fn timeout_discards_partial_frame() {
+        let mut reader = StallingReader(&[consts::RQ_ANALOG_WRITE_0, 0x01]);
+        let report = block_on(read_request_with_timeout(&mut reader, &mut Expired, 100))
+            .expect_err("partial frame was accepted");
+        assert!(matches!(
+            report.current_context(),
+            CommunicationError::Timeout
+        ));
+        assert_eq!(
+            report.downcast_ref::<Instruction>(),
+            Some(&Instruction(consts::RQ_ANALOG_WRITE_0))
+        );
+    }
+
+    #[test]
+    fn timeout_accepts_complete_frame() {
+        let mut reader = YieldingReader(&[consts::RQ_ANALOG_WRITE_1, 0xFF, 0x03]);
+        assert_eq!(
+            block_on(read_request_with_timeout(&mut reader, &mut Never, 100))
+                .expect("complete frame was rejected"),
+            Ok(Request::AnalogWrite(AnalogWritePort::Port2, 1023))
+        );
+    }
+
+    #[test]
+    fn timeout_expires_between_slow_bytes() {
+        let mut reader = YieldingReader(&[consts::RQ_ANALOG_WRITE_1, 0xFF, 0x03]);
+        let report = block_on(read_request_with_timeout(&mut reader, &mut Expired, 100))
+            .expect_err("stalled frame was accepted");
+        assert!(matches!(
+            report.current_context(),
+            CommunicationError::Timeout
+        ));
+    }
+
+    #[test]
+    fn zero_timeout_waits_forever() {
+        let mut reader = YieldingReader(&[consts::RQ_ANALOG_WRITE_1, 0xFF, 0x03]);
+        assert_eq!(
+            block_on(read_request_with_timeout(&mut reader, &mut Expired, 0))
+                .expect("slow frame was rejected"),
+            Ok(Request::AnalogWrite(AnalogWritePort::Port2, 1023))
+        );
+    }
+}
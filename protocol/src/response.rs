@@ -0,0 +1,1112 @@
+use crate::request::analog_read_port;
+use crate::{consts, flush, read_array, write_all, AnalogReadPort, CommunicationError, Request};
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_io_async::{Read, Write};
+use error_stack::Report;
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BuildProfile {
+    Debug = 0,
+    Release = 1,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Runtime {
+    Tokio = 0,
+    Embassy = 1,
+}
+
+/// Identifies the board and the firmware running on it
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct BoardInfo {
+    /// major, minor, patch
+    pub firmware_version: (u8, u8, u8),
+    pub build_profile: BuildProfile,
+    pub runtime: Runtime,
+    /// `esp_chip_model_t` of the chip
+    pub chip_model: u8,
+    /// major * 100 + minor
+    pub chip_revision: u16,
+    /// major, minor, patch
+    pub idf_version: (u8, u8, u8),
+    /// Factory programmed base MAC address
+    pub serial_number: [u8; 6],
+}
+
+/// Reason of an [Response::ExtendedError]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    UnknownInstruction = 1,
+    InvalidArgument = 2,
+    /// The request is valid but this hardware can not execute it
+    Unsupported = 3,
+    PeripheralFailure = 4,
+    /// The rest of the frame did not arrive in time
+    Timeout = 5,
+}
+
+impl ErrorCode {
+    fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(ErrorCode::UnknownInstruction),
+            2 => Some(ErrorCode::InvalidArgument),
+            3 => Some(ErrorCode::Unsupported),
+            4 => Some(ErrorCode::PeripheralFailure),
+            5 => Some(ErrorCode::Timeout),
+            _ => None,
+        }
+    }
+}
+
+/// How the board reports errors, B15 hosts only understand [ErrorMode::Legacy]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorMode {
+    /// A single [consts::MSG_ERROR] byte
+    #[default]
+    Legacy = 0,
+    /// [Response::ExtendedError] with code and instruction. Bare values get a
+    /// [consts::MSG_OK] in front so an error can take their place.
+    Extended = 1,
+}
+
+impl ErrorMode {
+    /// Error response for a failure of `instruction` as understood by the host
+    pub fn response(self, code: ErrorCode, instruction: u8) -> Response {
+        match self {
+            ErrorMode::Legacy => Response::Error,
+            ErrorMode::Extended => Response::ExtendedError { code, instruction },
+        }
+    }
+}
+
+/// Peripherals this board offers, bit n of a mask stands for port or pin n
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Analog read ports backed by an ADC channel, bit 0 is `AnalogReadPort::Port1`
+    pub analog_read_ports: u8,
+    /// Analog write ports, bit 0 is `AnalogWritePort::Port1`
+    pub analog_write_ports: u8,
+    /// Pins of `DigitalPort::Port1`
+    pub digital_port1_pins: u8,
+    /// Pins of `DigitalPort::Port2`
+    pub digital_port2_pins: u8,
+    /// DIP switches [Request::ReadDipSwitch] reports, bit n is switch n
+    pub dip_switches: u8,
+}
+
+/// Result of [Request::AnalogReadStats], min and max are in the scale of the samples while
+/// mean and standard deviation have [consts::STATS_FRACTION_BITS] fractional bits on top
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct AnalogStats {
+    pub mean: u16,
+    pub min: u16,
+    pub max: u16,
+    pub std_dev: u16,
+}
+
+/// Raw 12 bit reading of a streamed port, sent as `port << 12 | value`
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct StreamSample {
+    pub port: AnalogReadPort,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    Error,
+    /// Error with its reason, replaces [Response::Error] once the host asked for it
+    ExtendedError {
+        code: ErrorCode,
+        instruction: u8,
+    },
+    TestEcho(u8),
+    Info(BoardInfo),
+    /// Answer to [Request::IntTest] carrying the transformed value
+    IntTestResult(u16),
+    /// Per pin pass bitmap of the loopback self-test for each direction
+    SelfTest {
+        a_to_b: u8,
+        b_to_a: u8,
+    },
+    AnalogValue(u16),
+    /// Samples of [Request::AdcDacStroke] in sweep order
+    AnalogValues(Vec<u16>),
+    /// Frequency the PWM timer actually runs at and the value that results in 100% duty
+    PwmFrequency {
+        frequency: u32,
+        top: u16,
+    },
+    DigitalValue(u8),
+    Capabilities(Capabilities),
+    /// Calibrated voltage answering [Request::AnalogReadMillivolts] and [Request::FullScale]
+    Millivolts(u16),
+    AnalogStats(AnalogStats),
+    /// Samples pushed while streaming in conversion order, `dropped` counts the samples
+    /// discarded since the start because the host connection could not keep up
+    StreamBlock {
+        dropped: u32,
+        samples: Vec<StreamSample>,
+    },
+    /// Answer to [Request::StreamStop] with the final drop count
+    StreamStopped {
+        dropped: u32,
+    },
+    /// Register content answering [Request::GetMem8], [Request::SetMem8] echoes the value
+    Memory8(u8),
+    /// Like [Response::Memory8] for the 16 bit requests
+    Memory16(u16),
+    /// Answer to [Request::AnalogReadAll], one value per bit set in `ports` in port order
+    AnalogScan {
+        /// Bit n stands for `AnalogReadPort` n + 1
+        ports: u8,
+        values: Vec<u16>,
+    },
+}
+
+const INFO_LENGTH: usize = 17;
+const CAPABILITIES_LENGTH: usize = 5;
+const STATS_LENGTH: usize = 8;
+
+impl BoardInfo {
+    fn to_bytes(&self) -> [u8; INFO_LENGTH] {
+        let (fw_major, fw_minor, fw_patch) = self.firmware_version;
+        let (idf_major, idf_minor, idf_patch) = self.idf_version;
+        let [revision_low, revision_high] = self.chip_revision.to_le_bytes();
+        let [s0, s1, s2, s3, s4, s5] = self.serial_number;
+        [
+            fw_major,
+            fw_minor,
+            fw_patch,
+            self.build_profile as u8,
+            self.runtime as u8,
+            self.chip_model,
+            revision_low,
+            revision_high,
+            idf_major,
+            idf_minor,
+            idf_patch,
+            s0,
+            s1,
+            s2,
+            s3,
+            s4,
+            s5,
+        ]
+    }
+
+    fn from_bytes(bytes: [u8; INFO_LENGTH]) -> Option<Self> {
+        let [fw_major, fw_minor, fw_patch, build_profile, runtime, chip_model, revision_low, revision_high, idf_major, idf_minor, idf_patch, s0, s1, s2, s3, s4, s5] =
+            bytes;
+        let build_profile = match build_profile {
+            0 => BuildProfile::Debug,
+            1 => BuildProfile::Release,
+            _ => return None,
+        };
+        let runtime = match runtime {
+            0 => Runtime::Tokio,
+            1 => Runtime::Embassy,
+            _ => return None,
+        };
+        Some(Self {
+            firmware_version: (fw_major, fw_minor, fw_patch),
+            build_profile,
+            runtime,
+            chip_model,
+            chip_revision: u16::from_le_bytes([revision_low, revision_high]),
+            idf_version: (idf_major, idf_minor, idf_patch),
+            serial_number: [s0, s1, s2, s3, s4, s5],
+        })
+    }
+}
+
+impl Capabilities {
+    fn to_bytes(&self) -> [u8; CAPABILITIES_LENGTH] {
+        [
+            self.analog_read_ports,
+            self.analog_write_ports,
+            self.digital_port1_pins,
+            self.digital_port2_pins,
+            self.dip_switches,
+        ]
+    }
+
+    fn from_bytes(bytes: [u8; CAPABILITIES_LENGTH]) -> Self {
+        let [analog_read_ports, analog_write_ports, digital_port1_pins, digital_port2_pins, dip_switches] =
+            bytes;
+        Self {
+            analog_read_ports,
+            analog_write_ports,
+            digital_port1_pins,
+            digital_port2_pins,
+            dip_switches,
+        }
+    }
+}
+
+impl StreamSample {
+    fn to_bits(self) -> u16 {
+        (self.port as u16) << 12 | self.value & 0x0FFF
+    }
+
+    fn from_bits(bits: u16) -> Option<Self> {
+        Some(Self {
+            port: analog_read_port((bits >> 12) as u8)?,
+            value: bits & 0x0FFF,
+        })
+    }
+}
+
+impl AnalogStats {
+    fn to_bytes(self) -> [u8; STATS_LENGTH] {
+        let [mean_low, mean_high] = self.mean.to_le_bytes();
+        let [min_low, min_high] = self.min.to_le_bytes();
+        let [max_low, max_high] = self.max.to_le_bytes();
+        let [std_dev_low, std_dev_high] = self.std_dev.to_le_bytes();
+        [
+            mean_low,
+            mean_high,
+            min_low,
+            min_high,
+            max_low,
+            max_high,
+            std_dev_low,
+            std_dev_high,
+        ]
+    }
+
+    fn from_bytes(bytes: [u8; STATS_LENGTH]) -> Self {
+        let [mean_low, mean_high, min_low, min_high, max_low, max_high, std_dev_low, std_dev_high] =
+            bytes;
+        Self {
+            mean: u16::from_le_bytes([mean_low, mean_high]),
+            min: u16::from_le_bytes([min_low, min_high]),
+            max: u16::from_le_bytes([max_low, max_high]),
+            std_dev: u16::from_le_bytes([std_dev_low, std_dev_high]),
+        }
+    }
+}
+
+/// Writes `response` the way B15 hosts expect it, bare values go without a status byte
+pub async fn write_response<W: Write>(
+    writer: &mut W,
+    response: Response,
+) -> error_stack::Result<(), CommunicationError> {
+    queue_response(writer, response).await?;
+    flush(writer).await
+}
+
+/// Like [write_response] for a host that selected `mode`, see [ErrorMode::Extended]
+pub async fn write_response_in_mode<W: Write>(
+    writer: &mut W,
+    mode: ErrorMode,
+    response: Response,
+) -> error_stack::Result<(), CommunicationError> {
+    if mode == ErrorMode::Extended && !response.has_status() {
+        write_all(writer, &[consts::MSG_OK]).await?;
+    }
+    write_response(writer, response).await
+}
+
+/// Like [write_response] but returns once `writer` took the bytes instead of waiting until
+/// they are sent, for responses that follow each other like stream blocks
+pub async fn queue_response<W: Write>(
+    writer: &mut W,
+    response: Response,
+) -> error_stack::Result<(), CommunicationError> {
+    match response {
+        Response::Ok => write_all(writer, &[consts::MSG_OK]).await?,
+        Response::TestEcho(value) => write_all(writer, &[consts::MSG_OK, value]).await?,
+        Response::Info(info) => {
+            let payload = info.to_bytes();
+            // The length prefix allows appending fields without breaking older hosts
+            write_all(writer, &[consts::MSG_OK, payload.len() as u8]).await?;
+            write_all(writer, &payload).await?;
+        }
+        Response::Capabilities(capabilities) => {
+            let payload = capabilities.to_bytes();
+            write_all(writer, &[consts::MSG_OK, payload.len() as u8]).await?;
+            write_all(writer, &payload).await?;
+        }
+        Response::AnalogStats(stats) => {
+            let payload = stats.to_bytes();
+            write_all(writer, &[consts::MSG_OK, payload.len() as u8]).await?;
+            write_all(writer, &payload).await?;
+        }
+        Response::StreamBlock { dropped, samples } => {
+            let [d0, d1, d2, d3] = dropped.to_le_bytes();
+            write_all(
+                writer,
+                &[
+                    consts::MSG_STREAM_BLOCK,
+                    samples.len() as u8,
+                    d0,
+                    d1,
+                    d2,
+                    d3,
+                ],
+            )
+            .await?;
+            for sample in samples {
+                write_all(writer, &sample.to_bits().to_le_bytes()).await?;
+            }
+        }
+        Response::AnalogScan { ports, values } => {
+            write_all(writer, &[consts::MSG_OK, ports]).await?;
+            for value in values {
+                write_all(writer, &value.to_le_bytes()).await?;
+            }
+        }
+        Response::StreamStopped { dropped } => {
+            let [d0, d1, d2, d3] = dropped.to_le_bytes();
+            write_all(writer, &[consts::MSG_OK, d0, d1, d2, d3]).await?
+        }
+        Response::IntTestResult(value) => write_all(writer, &value.to_le_bytes()).await?,
+        Response::SelfTest { a_to_b, b_to_a } => {
+            write_all(writer, &[consts::MSG_OK, a_to_b, b_to_a]).await?
+        }
+        Response::AnalogValue(value) => write_all(writer, &value.to_le_bytes()).await?,
+        Response::AnalogValues(values) => {
+            for value in values {
+                write_all(writer, &value.to_le_bytes()).await?;
+            }
+            write_all(writer, &[consts::MSG_OK]).await?;
+        }
+        Response::PwmFrequency { frequency, top } => {
+            let [f0, f1, f2, f3] = frequency.to_le_bytes();
+            let [top_low, top_high] = top.to_le_bytes();
+            write_all(writer, &[consts::MSG_OK, f0, f1, f2, f3, top_low, top_high]).await?
+        }
+        Response::DigitalValue(value) => write_all(writer, &value.to_le_bytes()).await?,
+        Response::Memory8(value) => write_all(writer, &[value]).await?,
+        Response::Memory16(value) => write_all(writer, &value.to_le_bytes()).await?,
+        Response::Millivolts(value) => {
+            let [low, high] = value.to_le_bytes();
+            write_all(writer, &[consts::MSG_OK, low, high]).await?
+        }
+        Response::Error => write_all(writer, &[consts::MSG_ERROR]).await?,
+        Response::ExtendedError { code, instruction } => {
+            write_all(
+                writer,
+                &[consts::MSG_EXTENDED_ERROR, code as u8, instruction],
+            )
+            .await?
+        }
+    }
+    Ok(())
+}
+
+impl Response {
+    /// Counterpart of [Request::has_status]
+    fn has_status(&self) -> bool {
+        !matches!(
+            self,
+            Response::IntTestResult(_)
+                | Response::AnalogValue(_)
+                | Response::DigitalValue(_)
+                | Response::AnalogValues(_)
+                | Response::Memory8(_)
+                | Response::Memory16(_)
+        )
+    }
+}
+
+impl Request {
+    /// Bare values (`IntTest`, `AnalogRead`, `ReadDipSwitch`, `DigitalRead`, the memory requests
+    /// and the samples of `AdcDacStroke`) are not preceded by a status byte outside of
+    /// [ErrorMode::Extended]
+    fn has_status(&self) -> bool {
+        !matches!(
+            self,
+            Request::IntTest(_)
+                | Request::AnalogRead(_)
+                | Request::ReadDipSwitch
+                | Request::DigitalRead(_)
+                | Request::AdcDacStroke { .. }
+                | Request::SetMem8 { .. }
+                | Request::GetMem8 { .. }
+                | Request::SetMem16 { .. }
+                | Request::GetMem16 { .. }
+        )
+    }
+
+    /// Answer refusing the request that keeps the host in sync
+    ///
+    /// Every request gets the error of `mode`, except for bare values in [ErrorMode::Legacy].
+    /// An error in their place can not be told apart from a value, they get a placeholder of
+    /// the expected size instead: 0, or the inverse of the value written for the memory
+    /// requests so the echo does not match. Hosts find unbacked ports through
+    /// [Request::Capabilities].
+    pub fn rejection(&self, mode: ErrorMode, code: ErrorCode) -> Response {
+        if mode == ErrorMode::Extended {
+            return mode.response(code, self.instruction());
+        }
+        match *self {
+            Request::IntTest(_) => Response::IntTestResult(0),
+            Request::AnalogRead(_) => Response::AnalogValue(0),
+            Request::ReadDipSwitch | Request::DigitalRead(_) => Response::DigitalValue(0),
+            Request::AdcDacStroke { steps, .. } => Response::AnalogValues(vec![0; steps as usize]),
+            Request::SetMem8 { value, .. } => Response::Memory8(!value),
+            Request::GetMem8 { .. } => Response::Memory8(0),
+            Request::SetMem16 { value, .. } => Response::Memory16(!value),
+            Request::GetMem16 { .. } => Response::Memory16(0),
+            _ => mode.response(code, self.instruction()),
+        }
+    }
+}
+
+/// Decodes the answer to `request` on the host side, the counterpart of [write_response]
+///
+/// Bare values are not preceded by a status byte, an error sent in their place can not be
+/// told apart from a value and will desynchronize the stream, see [Request::rejection].
+/// Blocks still in flight when answering [Request::StreamStop] are skipped, use
+/// [read_stream] to receive them.
+pub async fn read_response<R: Read>(
+    reader: &mut R,
+    request: &Request,
+) -> error_stack::Result<Response, CommunicationError> {
+    read_response_in_mode(reader, ErrorMode::Legacy, request).await
+}
+
+/// Like [read_response] after the host selected `mode`, the counterpart of
+/// [write_response_in_mode]
+pub async fn read_response_in_mode<R: Read>(
+    reader: &mut R,
+    mode: ErrorMode,
+    request: &Request,
+) -> error_stack::Result<Response, CommunicationError> {
+    if let Request::StreamStop = request {
+        loop {
+            match read_stream(reader).await? {
+                Response::StreamBlock { .. } => {}
+                response => return Ok(response),
+            }
+        }
+    }
+    if let Request::Discard = request {
+        // Discard ends a running stream too, blocks in flight arrive before the answer
+        loop {
+            let [status] = read_array(reader).await?;
+            if status != consts::MSG_STREAM_BLOCK {
+                return Ok(decode_status(reader, status).await?.unwrap_or(Response::Ok));
+            }
+            read_stream_block(reader).await?;
+        }
+    }
+    if request.has_status() || mode == ErrorMode::Extended {
+        if let Some(error) = read_status(reader).await? {
+            return Ok(error);
+        }
+    }
+    match *request {
+        Request::Discard
+        | Request::AnalogWrite(_, _)
+        | Request::PwmSetValue(_)
+        | Request::DigitalWrite(_, _)
+        | Request::SetDirection(_, _)
+        | Request::SetPull { .. }
+        | Request::DigitalUpdate(_, _)
+        | Request::SetErrorMode(_)
+        | Request::SetInterByteTimeout(_)
+        | Request::SetAdcMode(_)
+        | Request::SetAttenuation(_, _)
+        | Request::StreamStart { .. } => Ok(Response::Ok),
+        Request::InitTest(_) => {
+            let [value] = read_array(reader).await?;
+            Ok(Response::TestEcho(value))
+        }
+        Request::Info => {
+            let info =
+                BoardInfo::from_bytes(read_length_prefixed(reader).await?).ok_or_else(|| {
+                    Report::new(CommunicationError::InvalidResponse)
+                        .attach_printable("unknown build profile or runtime")
+                })?;
+            Ok(Response::Info(info))
+        }
+        Request::Capabilities => Ok(Response::Capabilities(Capabilities::from_bytes(
+            read_length_prefixed(reader).await?,
+        ))),
+        Request::AnalogReadStats { .. } => Ok(Response::AnalogStats(AnalogStats::from_bytes(
+            read_length_prefixed(reader).await?,
+        ))),
+        Request::IntTest(_) => Ok(Response::IntTestResult(u16::from_le_bytes(
+            read_array(reader).await?,
+        ))),
+        Request::SelfTest => {
+            let [a_to_b, b_to_a] = read_array(reader).await?;
+            Ok(Response::SelfTest { a_to_b, b_to_a })
+        }
+        Request::ReadDipSwitch | Request::DigitalRead(_) => {
+            let [value] = read_array(reader).await?;
+            Ok(Response::DigitalValue(value))
+        }
+        Request::AnalogRead(_) => Ok(Response::AnalogValue(u16::from_le_bytes(
+            read_array(reader).await?,
+        ))),
+        Request::AnalogReadMillivolts(_) | Request::FullScale(_) => Ok(Response::Millivolts(
+            u16::from_le_bytes(read_array(reader).await?),
+        )),
+        Request::AdcDacStroke { steps, .. } => {
+            let mut samples = Vec::with_capacity(steps as usize);
+            for _ in 0..steps {
+                samples.push(u16::from_le_bytes(read_array(reader).await?));
+            }
+            if read_status(reader).await?.is_some() {
+                return Err(Report::new(CommunicationError::InvalidResponse)
+                    .attach_printable("stroke samples are not terminated by MSG_OK"));
+            }
+            Ok(Response::AnalogValues(samples))
+        }
+        Request::PwmSetFrequency(_) => {
+            let [f0, f1, f2, f3, top_low, top_high] = read_array(reader).await?;
+            Ok(Response::PwmFrequency {
+                frequency: u32::from_le_bytes([f0, f1, f2, f3]),
+                top: u16::from_le_bytes([top_low, top_high]),
+            })
+        }
+        Request::SetMem8 { .. } | Request::GetMem8 { .. } => {
+            let [value] = read_array(reader).await?;
+            Ok(Response::Memory8(value))
+        }
+        Request::SetMem16 { .. } | Request::GetMem16 { .. } => Ok(Response::Memory16(
+            u16::from_le_bytes(read_array(reader).await?),
+        )),
+        Request::AnalogReadAll => {
+            let [ports] = read_array(reader).await?;
+            let mut values = Vec::with_capacity(ports.count_ones() as usize);
+            for _ in 0..ports.count_ones() {
+                values.push(u16::from_le_bytes(read_array(reader).await?));
+            }
+            Ok(Response::AnalogScan { ports, values })
+        }
+        Request::StreamStop => unreachable!("answered before the status byte"),
+    }
+}
+
+/// Receives the next message pushed after [Request::StreamStart]
+///
+/// Evaluates to [Response::StreamBlock] until the board answers [Request::StreamStop] with
+/// [Response::StreamStopped], errors reported by the board end the stream as well.
+pub async fn read_stream<R: Read>(
+    reader: &mut R,
+) -> error_stack::Result<Response, CommunicationError> {
+    let [status] = read_array(reader).await?;
+    if status != consts::MSG_STREAM_BLOCK {
+        if let Some(error) = decode_status(reader, status).await? {
+            return Ok(error);
+        }
+        let dropped = u32::from_le_bytes(read_array(reader).await?);
+        return Ok(Response::StreamStopped { dropped });
+    }
+    read_stream_block(reader).await
+}
+
+/// Reads a [Response::StreamBlock] after its message byte
+async fn read_stream_block<R: Read>(
+    reader: &mut R,
+) -> error_stack::Result<Response, CommunicationError> {
+    let [count, d0, d1, d2, d3] = read_array(reader).await?;
+    let mut samples = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let bits = u16::from_le_bytes(read_array(reader).await?);
+        let sample = StreamSample::from_bits(bits).ok_or_else(|| {
+            Report::new(CommunicationError::InvalidResponse)
+                .attach_printable(format!("unknown port in stream sample {bits:#x}"))
+        })?;
+        samples.push(sample);
+    }
+    Ok(Response::StreamBlock {
+        dropped: u32::from_le_bytes([d0, d1, d2, d3]),
+        samples,
+    })
+}
+
+/// Reads a payload with length prefix, skipping fields appended by newer firmware
+async fn read_length_prefixed<R: Read, const N: usize>(
+    reader: &mut R,
+) -> error_stack::Result<[u8; N], CommunicationError> {
+    let [length] = read_array(reader).await?;
+    if (length as usize) < N {
+        return Err(Report::new(CommunicationError::InvalidResponse)
+            .attach_printable(format!("payload of {length} bytes is too short")));
+    }
+    let payload = read_array(reader).await?;
+    for _ in N..length as usize {
+        let [_] = read_array(reader).await?;
+    }
+    Ok(payload)
+}
+
+/// Reads a status byte, evaluates to the error response unless it is [consts::MSG_OK]
+async fn read_status<R: Read>(
+    reader: &mut R,
+) -> error_stack::Result<Option<Response>, CommunicationError> {
+    let [status] = read_array(reader).await?;
+    decode_status(reader, status).await
+}
+
+/// Like [read_status] for a status byte that was already received
+async fn decode_status<R: Read>(
+    reader: &mut R,
+    status: u8,
+) -> error_stack::Result<Option<Response>, CommunicationError> {
+    match status {
+        consts::MSG_OK => Ok(None),
+        consts::MSG_ERROR => Ok(Some(Response::Error)),
+        consts::MSG_EXTENDED_ERROR => {
+            let [code, instruction] = read_array(reader).await?;
+            let code = ErrorCode::from_u8(code).ok_or_else(|| {
+                Report::new(CommunicationError::InvalidResponse)
+                    .attach_printable(format!("unknown error code {code}"))
+            })?;
+            Ok(Some(Response::ExtendedError { code, instruction }))
+        }
+        status => Err(Report::new(CommunicationError::InvalidResponse)
+            .attach_printable(format!("unexpected status byte {status:#x}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AnalogWritePort;
+    use embassy_futures::block_on;
+
+    fn encode(response: Response) -> Vec<u8> {
+        let mut writer = Vec::new();
+        block_on(write_response(&mut writer, response)).expect("encoding failed");
+        writer
+    }
+
+    fn decode(bytes: &[u8], request: &Request) -> Response {
+        let mut reader = bytes;
+        let response = block_on(read_response(&mut reader, request)).expect("decoding failed");
+        assert!(reader.is_empty(), "response was not consumed completely");
+        response
+    }
+
+    #[test]
+    fn encode_status_responses() {
+        assert_eq!(encode(Response::Ok), [consts::MSG_OK]);
+        assert_eq!(encode(Response::Error), [consts::MSG_ERROR]);
+        assert_eq!(
+            encode(Response::ExtendedError {
+                code: ErrorCode::Timeout,
+                instruction: consts::RQ_ANALOG_WRITE_0
+            }),
+            [consts::MSG_EXTENDED_ERROR, 5, consts::RQ_ANALOG_WRITE_0]
+        );
+        assert_eq!(encode(Response::TestEcho(0x42)), [consts::MSG_OK, 0x42]);
+        assert_eq!(encode(Response::IntTestResult(0x1234)), [0x34, 0x12]);
+        assert_eq!(
+            encode(Response::SelfTest {
+                a_to_b: 0x7F,
+                b_to_a: 0x3F
+            }),
+            [consts::MSG_OK, 0x7F, 0x3F]
+        );
+    }
+
+    #[test]
+    fn encode_info_response() {
+        let info = BoardInfo {
+            firmware_version: (0, 1, 2),
+            build_profile: BuildProfile::Release,
+            runtime: Runtime::Embassy,
+            chip_model: 13,
+            chip_revision: 0x0102,
+            idf_version: (5, 2, 2),
+            serial_number: [0x40, 0x4C, 0xCA, 0x01, 0x02, 0x03],
+        };
+        assert_eq!(
+            encode(Response::Info(info)),
+            [
+                consts::MSG_OK,
+                17,
+                0,
+                1,
+                2,
+                1,
+                1,
+                13,
+                0x02,
+                0x01,
+                5,
+                2,
+                2,
+                0x40,
+                0x4C,
+                0xCA,
+                0x01,
+                0x02,
+                0x03
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_capabilities_response() {
+        assert_eq!(
+            encode(Response::Capabilities(Capabilities {
+                analog_read_ports: 0x3F,
+                analog_write_ports: 0x03,
+                digital_port1_pins: 0x7F,
+                digital_port2_pins: 0xFF,
+                dip_switches: 0x01,
+            })),
+            [consts::MSG_OK, 5, 0x3F, 0x03, 0x7F, 0xFF, 0x01]
+        );
+    }
+
+    #[test]
+    fn encode_stats_response() {
+        assert_eq!(
+            encode(Response::AnalogStats(AnalogStats {
+                mean: 0x1234,
+                min: 0x0120,
+                max: 0x0125,
+                std_dev: 0x0018,
+            })),
+            [
+                consts::MSG_OK,
+                8,
+                0x34,
+                0x12,
+                0x20,
+                0x01,
+                0x25,
+                0x01,
+                0x18,
+                0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_value_responses() {
+        assert_eq!(encode(Response::AnalogValue(0x0ABC)), [0xBC, 0x0A]);
+        assert_eq!(
+            encode(Response::AnalogValues(vec![1, 0x0200])),
+            [0x01, 0x00, 0x00, 0x02, consts::MSG_OK]
+        );
+        assert_eq!(
+            encode(Response::PwmFrequency {
+                frequency: 1_000,
+                top: 0x2000
+            }),
+            [consts::MSG_OK, 0xE8, 0x03, 0x00, 0x00, 0x00, 0x20]
+        );
+        assert_eq!(encode(Response::DigitalValue(0x81)), [0x81]);
+        assert_eq!(encode(Response::Memory8(0x42)), [0x42]);
+        assert_eq!(encode(Response::Memory16(0x1234)), [0x34, 0x12]);
+        assert_eq!(
+            encode(Response::Millivolts(3300)),
+            [consts::MSG_OK, 0xE4, 0x0C]
+        );
+    }
+
+    #[test]
+    fn decode_info_with_appended_fields() {
+        let response = decode(
+            &[
+                consts::MSG_OK,
+                19,
+                1,
+                2,
+                3,
+                0,
+                1,
+                13,
+                0x00,
+                0x01,
+                5,
+                2,
+                2,
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+                0xAA,
+                0xBB,
+            ],
+            &Request::Info,
+        );
+        assert_eq!(
+            response,
+            Response::Info(BoardInfo {
+                firmware_version: (1, 2, 3),
+                build_profile: BuildProfile::Debug,
+                runtime: Runtime::Embassy,
+                chip_model: 13,
+                chip_revision: 0x0100,
+                idf_version: (5, 2, 2),
+                serial_number: [1, 2, 3, 4, 5, 6],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_invalid_status() {
+        let mut reader: &[u8] = &[0x00];
+        assert!(block_on(read_response(&mut reader, &Request::Discard)).is_err());
+    }
+
+    #[test]
+    fn encode_analog_scan() {
+        assert_eq!(
+            encode(Response::AnalogScan {
+                ports: 0b101,
+                values: vec![0x0123, 0x0FFF],
+            }),
+            [consts::MSG_OK, 0b101, 0x23, 0x01, 0xFF, 0x0F]
+        );
+    }
+
+    #[test]
+    fn encode_stream_block() {
+        assert_eq!(
+            encode(Response::StreamBlock {
+                dropped: 0x0102,
+                samples: vec![
+                    StreamSample {
+                        port: AnalogReadPort::Port1,
+                        value: 0x0FFF
+                    },
+                    StreamSample {
+                        port: AnalogReadPort::Port6,
+                        value: 0x0123
+                    },
+                ],
+            }),
+            [
+                consts::MSG_STREAM_BLOCK,
+                2,
+                0x02,
+                0x01,
+                0x00,
+                0x00,
+                0xFF,
+                0x0F,
+                0x23,
+                0x51
+            ]
+        );
+    }
+
+    /// Records how often it was flushed
+    #[derive(Default)]
+    struct FlushCounter {
+        bytes: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl embedded_io_async::ErrorType for FlushCounter {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Write for FlushCounter {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn queued_responses_are_not_flushed() {
+        let block = Response::StreamBlock {
+            dropped: 3,
+            samples: vec![StreamSample {
+                port: AnalogReadPort::Port4,
+                value: 0x0456,
+            }],
+        };
+        let mut writer = FlushCounter::default();
+        block_on(queue_response(&mut writer, block.clone())).expect("encoding failed");
+        assert_eq!(writer.flushes, 0);
+        assert_eq!(writer.bytes, encode(block));
+        block_on(write_response(&mut writer, Response::Ok)).expect("encoding failed");
+        assert_eq!(writer.flushes, 1);
+    }
+
+    #[test]
+    fn stream_stop_skips_blocks_in_flight() {
+        let mut bytes = encode(Response::StreamBlock {
+            dropped: 0,
+            samples: vec![StreamSample {
+                port: AnalogReadPort::Port2,
+                value: 42,
+            }],
+        });
+        bytes.extend(encode(Response::StreamStopped { dropped: 7 }));
+        assert_eq!(
+            decode(&bytes, &Request::StreamStop),
+            Response::StreamStopped { dropped: 7 }
+        );
+
+        let mut reader = bytes.as_slice();
+        assert!(matches!(
+            block_on(read_stream(&mut reader)),
+            Ok(Response::StreamBlock { .. })
+        ));
+        assert_eq!(
+            block_on(read_stream(&mut reader)).ok(),
+            Some(Response::StreamStopped { dropped: 7 })
+        );
+    }
+
+    #[test]
+    fn host_survives_rejections() {
+        let requests = [
+            Request::AnalogRead(AnalogReadPort::Port8),
+            Request::AdcDacStroke {
+                write_port: AnalogWritePort::Port1,
+                read_port: AnalogReadPort::Port7,
+                steps: 3,
+                settle_time_us: 0,
+            },
+            Request::GetMem16 { address: 0x26 },
+            Request::SetMem8 {
+                address: 0x26,
+                value: 0x0F,
+            },
+            Request::AnalogReadMillivolts(AnalogReadPort::Port8),
+        ];
+        for mode in [ErrorMode::Legacy, ErrorMode::Extended] {
+            let mut bytes = Vec::new();
+            for request in &requests {
+                let response = request.rejection(mode, ErrorCode::Unsupported);
+                block_on(write_response_in_mode(&mut bytes, mode, response))
+                    .expect("encoding failed");
+            }
+            // The answer to the next request must still be found
+            bytes.extend(encode(Response::TestEcho(0x42)));
+
+            let mut reader = bytes.as_slice();
+            let responses: Vec<_> = requests
+                .iter()
+                .map(|request| block_on(read_response_in_mode(&mut reader, mode, request)).unwrap())
+                .collect();
+            match mode {
+                ErrorMode::Legacy => {
+                    assert_eq!(responses[0], Response::AnalogValue(0));
+                    assert_eq!(responses[1], Response::AnalogValues(vec![0; 3]));
+                    assert_eq!(responses[2], Response::Memory16(0));
+                    // A B15 host compares the echo with the value it wrote
+                    assert_eq!(responses[3], Response::Memory8(0xF0));
+                }
+                ErrorMode::Extended => {
+                    for (request, response) in requests.iter().zip(&responses) {
+                        assert_eq!(
+                            *response,
+                            mode.response(ErrorCode::Unsupported, request.instruction())
+                        );
+                    }
+                }
+            }
+            assert_eq!(
+                responses[4],
+                mode.response(ErrorCode::Unsupported, consts::RQ_ANALOG_READ_MV)
+            );
+            assert_eq!(
+                block_on(read_response(&mut reader, &Request::InitTest(0x42))).ok(),
+                Some(Response::TestEcho(0x42))
+            );
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn extended_mode_frames_bare_values() {
+        let cases = [
+            (
+                Request::AnalogRead(AnalogReadPort::Port1),
+                // Starts like an extended error, the status byte in front tells them apart
+                Response::AnalogValue(u16::from(consts::MSG_EXTENDED_ERROR)),
+            ),
+            (
+                Request::AdcDacStroke {
+                    write_port: AnalogWritePort::Port2,
+                    read_port: AnalogReadPort::Port3,
+                    steps: 2,
+                    settle_time_us: 10,
+                },
+                Response::AnalogValues(vec![1, 4095]),
+            ),
+            (
+                Request::SetMem16 {
+                    address: 0x25,
+                    value: 0x1234,
+                },
+                Response::Memory16(0x1234),
+            ),
+        ];
+        for (request, response) in cases {
+            let mut bytes = Vec::new();
+            block_on(write_response_in_mode(
+                &mut bytes,
+                ErrorMode::Extended,
+                response.clone(),
+            ))
+            .expect("encoding failed");
+            assert_eq!(bytes[0], consts::MSG_OK);
+            assert_eq!(bytes[1..], encode(response.clone()));
+            let mut reader = bytes.as_slice();
+            let decoded = block_on(read_response_in_mode(
+                &mut reader,
+                ErrorMode::Extended,
+                &request,
+            ));
+            assert_eq!(decoded.ok(), Some(response));
+            assert!(reader.is_empty());
+        }
+        // Responses with a status byte are the same in both modes
+        let mut bytes = Vec::new();
+        block_on(write_response_in_mode(
+            &mut bytes,
+            ErrorMode::Extended,
+            Response::Ok,
+        ))
+        .expect("encoding failed");
+        assert_eq!(bytes, [consts::MSG_OK]);
+    }
+
+    #[test]
+    fn discard_ends_stream() {
+        let mut bytes = encode(Response::StreamBlock {
+            dropped: 3,
+            samples: vec![StreamSample {
+                port: AnalogReadPort::Port1,
+                value: 4095,
+            }],
+        });
+        bytes.extend(encode(Response::Ok));
+        assert_eq!(decode(&bytes, &Request::Discard), Response::Ok);
+        // Without a stream the answer is the same as before
+        assert_eq!(decode(&[consts::MSG_OK], &Request::Discard), Response::Ok);
+    }
+
+    #[test]
+    fn stream_ends_with_error() {
+        let mut reader: &[u8] = &[consts::MSG_EXTENDED_ERROR, 4, consts::NO_INSTRUCTION];
+        assert_eq!(
+            block_on(read_stream(&mut reader)).ok(),
+            Some(Response::ExtendedError {
+                code: ErrorCode::PeripheralFailure,
+                instruction: consts::NO_INSTRUCTION
+            })
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{consts, AnalogStats};
+
+/// Folds analog samples into [AnalogStats] without buffering them
+#[derive(Debug, Clone, Default)]
+pub struct SampleStats {
+    count: u32,
+    sum: u64,
+    sum_of_squares: u64,
+    min: u16,
+    max: u16,
+}
+
+impl SampleStats {
+    pub fn push(&mut self, sample: u16) {
+        if self.count == 0 {
+            self.min = sample;
+            self.max = sample;
+        } else {
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+        }
+        self.count += 1;
+        self.sum += sample as u64;
+        self.sum_of_squares += sample as u64 * sample as u64;
+    }
+
+    /// Mean and population standard deviation of the samples so far, `None` without samples
+    pub fn finish(&self) -> Option<AnalogStats> {
+        if self.count == 0 {
+            return None;
+        }
+        let count = self.count as u128;
+        let sum = self.sum as u128;
+        let scale = 1u128 << consts::STATS_FRACTION_BITS;
+        let mean = (sum * scale + count / 2) / count;
+        // n² · variance, kept in integers to stay exact for any sample count
+        let spread = count * self.sum_of_squares as u128 - sum * sum;
+        let std_dev = isqrt(spread * scale * scale / (count * count));
+        Some(AnalogStats {
+            mean: mean as u16,
+            min: self.min,
+            max: self.max,
+            std_dev: std_dev as u16,
+        })
+    }
+}
+
+fn isqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+    let mut root = value;
+    let mut next = root.div_ceil(2);
+    while next < root {
+        root = next;
+        next = (root + value / root) / 2;
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(samples: &[u16]) -> Option<AnalogStats> {
+        let mut stats = SampleStats::default();
+        for &sample in samples {
+            stats.push(sample);
+        }
+        stats.finish()
+    }
+
+    #[test]
+    fn no_samples() {
+        assert_eq!(stats(&[]), None);
+    }
+
+    #[test]
+    fn constant_samples() {
+        assert_eq!(
+            stats(&[4095; 1000]),
+            Some(AnalogStats {
+                mean: 4095 << consts::STATS_FRACTION_BITS,
+                min: 4095,
+                max: 4095,
+                std_dev: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn spread_samples() {
+        // Mean 5, standard deviation 2
+        assert_eq!(
+            stats(&[2, 4, 4, 4, 5, 5, 7, 9]),
+            Some(AnalogStats {
+                mean: 5 << consts::STATS_FRACTION_BITS,
+                min: 2,
+                max: 9,
+                std_dev: 2 << consts::STATS_FRACTION_BITS,
+            })
+        );
+        // Mean 1.5 and standard deviation 0.5 need the fractional bits
+        assert_eq!(
+            stats(&[1, 2]),
+            Some(AnalogStats {
+                mean: 24,
+                min: 1,
+                max: 2,
+                std_dev: 8,
+            })
+        );
+    }
+}
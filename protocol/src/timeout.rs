@@ -0,0 +1,45 @@
+use embassy_futures::select::{select, Either};
+use embedded_hal_async::delay::DelayNs;
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read};
+
+/// Reader that fails with [ErrorKind::TimedOut] when no byte arrives within `timeout_ms`
+///
+/// Every call to `read` restarts the deadline, so `read_exact` gives up once the gap
+/// between two bytes gets too large rather than after a fixed time for the whole buffer.
+/// A `timeout_ms` of 0 disables the deadline.
+pub(crate) struct InterByteTimeout<'a, R, D> {
+    pub(crate) reader: &'a mut R,
+    pub(crate) delay: &'a mut D,
+    pub(crate) timeout_ms: u32,
+}
+
+#[derive(Debug)]
+pub(crate) enum TimeoutError<E> {
+    TimedOut,
+    Other(E),
+}
+
+impl<E: Error> Error for TimeoutError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TimeoutError::TimedOut => ErrorKind::TimedOut,
+            TimeoutError::Other(error) => error.kind(),
+        }
+    }
+}
+
+impl<R: Read, D> ErrorType for InterByteTimeout<'_, R, D> {
+    type Error = TimeoutError<R::Error>;
+}
+
+impl<R: Read, D: DelayNs> Read for InterByteTimeout<'_, R, D> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.timeout_ms == 0 {
+            return self.reader.read(buf).await.map_err(TimeoutError::Other);
+        }
+        match select(self.reader.read(buf), self.delay.delay_ms(self.timeout_ms)).await {
+            Either::First(result) => result.map_err(TimeoutError::Other),
+            Either::Second(()) => Err(TimeoutError::TimedOut),
+        }
+    }
+}
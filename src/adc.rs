@@ -0,0 +1,105 @@
+//! Oneshot conversions of ADC1 and their calibration
+//!
+//! Stands in for the oneshot channel drivers of the hal, which take the pin for themselves and
+//! never delete their calibration scheme. Channels are configured by number here and every
+//! [Calibration] deletes its scheme when dropped.
+use esp_idf_svc::hal::adc::attenuation::adc_atten_t;
+use esp_idf_svc::hal::adc::ADC1;
+use esp_idf_svc::hal::peripheral::{Peripheral, PeripheralRef};
+use esp_idf_svc::sys::{
+    adc_bitwidth_t_ADC_BITWIDTH_DEFAULT, adc_cali_create_scheme_curve_fitting,
+    adc_cali_curve_fitting_config_t, adc_cali_delete_scheme_curve_fitting, adc_cali_handle_t,
+    adc_cali_raw_to_voltage, adc_channel_t, adc_oneshot_chan_cfg_t, adc_oneshot_config_channel,
+    adc_oneshot_del_unit, adc_oneshot_new_unit, adc_oneshot_read, adc_oneshot_unit_handle_t,
+    adc_oneshot_unit_init_cfg_t, adc_unit_t_ADC_UNIT_1, esp, EspError,
+};
+use log::warn;
+
+pub struct OneshotAdc<'d> {
+    handle: adc_oneshot_unit_handle_t,
+    adc: PeripheralRef<'d, ADC1>,
+}
+
+impl<'d> OneshotAdc<'d> {
+    pub fn new(adc: impl Peripheral<P = ADC1> + 'd) -> Result<Self, EspError> {
+        let adc = adc.into_ref();
+        let config = adc_oneshot_unit_init_cfg_t {
+            unit_id: adc_unit_t_ADC_UNIT_1,
+            ..Default::default()
+        };
+        let mut handle: adc_oneshot_unit_handle_t = core::ptr::null_mut();
+        esp!(unsafe { adc_oneshot_new_unit(&config, &mut handle) })?;
+        Ok(Self { handle, adc })
+    }
+
+    /// Converts `channel` with `attenuation` from now on, ESP-IDF switches its pad to analog mode
+    pub fn configure(
+        &self,
+        channel: adc_channel_t,
+        attenuation: adc_atten_t,
+    ) -> Result<(), EspError> {
+        let config = adc_oneshot_chan_cfg_t {
+            atten: attenuation,
+            bitwidth: adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
+        };
+        esp!(unsafe { adc_oneshot_config_channel(self.handle, channel, &config) })
+    }
+
+    /// Lends ADC1 to the continuous driver, oneshot conversions wait until it is returned
+    pub fn lend(&mut self) -> PeripheralRef<'_, ADC1> {
+        self.adc.reborrow()
+    }
+
+    pub fn read_raw(&self, channel: adc_channel_t) -> Result<u16, EspError> {
+        let mut raw = 0;
+        esp!(unsafe { adc_oneshot_read(self.handle, channel, &mut raw) })?;
+        Ok(raw as u16)
+    }
+}
+
+impl Drop for OneshotAdc<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = esp!(unsafe { adc_oneshot_del_unit(self.handle) }) {
+            warn!("Could not delete the oneshot ADC unit: {err:?}");
+        }
+    }
+}
+
+/// Curve fitting scheme of one channel at one attenuation
+pub struct Calibration {
+    handle: adc_cali_handle_t,
+}
+
+impl Calibration {
+    /// `None` if the eFuses of the chip carry no calibration data
+    pub fn new(channel: adc_channel_t, attenuation: adc_atten_t) -> Option<Self> {
+        let config = adc_cali_curve_fitting_config_t {
+            unit_id: adc_unit_t_ADC_UNIT_1,
+            chan: channel,
+            atten: attenuation,
+            bitwidth: adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
+        };
+        let mut handle: adc_cali_handle_t = core::ptr::null_mut();
+        match esp!(unsafe { adc_cali_create_scheme_curve_fitting(&config, &mut handle) }) {
+            Ok(()) => Some(Self { handle }),
+            Err(err) => {
+                warn!("No calibration for ADC channel {channel}: {err:?}");
+                None
+            }
+        }
+    }
+
+    pub fn millivolts(&self, raw: u16) -> Result<u16, EspError> {
+        let mut millivolts = 0;
+        esp!(unsafe { adc_cali_raw_to_voltage(self.handle, raw as i32, &mut millivolts) })?;
+        Ok(millivolts as u16)
+    }
+}
+
+impl Drop for Calibration {
+    fn drop(&mut self) {
+        if let Err(err) = esp!(unsafe { adc_cali_delete_scheme_curve_fitting(self.handle) }) {
+            warn!("Could not delete an ADC calibration scheme: {err:?}");
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use b32_protocol::{BoardInfo, BuildProfile, Runtime};
+use esp_idf_svc::sys::{
+    esp, esp_chip_info, esp_chip_info_t, esp_efuse_mac_get_default, EspError,
+    ESP_IDF_VERSION_MAJOR, ESP_IDF_VERSION_MINOR, ESP_IDF_VERSION_PATCH,
+};
+
+/// Collects the information reported by `RQ_INFO`
+pub fn info() -> Result<BoardInfo, EspError> {
+    let mut chip_info = esp_chip_info_t::default();
+    unsafe { esp_chip_info(&mut chip_info) };
+
+    let mut serial_number = [0u8; 6];
+    esp!(unsafe { esp_efuse_mac_get_default(serial_number.as_mut_ptr()) })?;
+
+    let build_profile = if cfg!(debug_assertions) {
+        BuildProfile::Debug
+    } else {
+        BuildProfile::Release
+    };
+    #[cfg(feature = "rt-tokio")]
+    let runtime = Runtime::Tokio;
+    #[cfg(feature = "rt-embassy")]
+    let runtime = Runtime::Embassy;
+
+    Ok(BoardInfo {
+        firmware_version: (
+            env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(u8::MAX),
+            env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(u8::MAX),
+            env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(u8::MAX),
+        ),
+        build_profile,
+        runtime,
+        chip_model: chip_info.model as u8,
+        chip_revision: chip_info.revision,
+        idf_version: (
+            ESP_IDF_VERSION_MAJOR as u8,
+            ESP_IDF_VERSION_MINOR as u8,
+            ESP_IDF_VERSION_PATCH as u8,
+        ),
+        serial_number,
+    })
+}
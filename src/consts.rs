@@ -1,26 +1,9 @@
-//Serial port settings
-pub const BAUD: u32 = 57600;
-
-pub const MSG_OK: u8 = 0xFF;
-pub const MSG_ERROR: u8 = 0xFE;
-pub const MAX_DATA_SIZE: u8 = 64;
-
-//Requests
-pub const RQ_DISCARD: u8 = 0;
-pub const RQ_TEST: u8 = 1;
-pub const RQ_INFO: u8 = 2;
-pub const RQ_INT_TEST: u8 = 3;
-pub const RQ_SELF_TEST: u8 = 4;
-pub const RQ_DIGITAL_WRITE_0: u8 = 5;
-pub const RQ_DIGITAL_WRITE_1: u8 = 6;
-pub const RQ_DIGITAL_READ_0: u8 = 7;
-pub const RQ_DIGITAL_READ_1: u8 = 8;
-pub const RQ_READ_DIP_SWITCH: u8 = 9;
-pub const RQ_ANALOG_WRITE_0: u8 = 10;
-pub const RQ_ANALOG_WRITE_1: u8 = 11;
-pub const RQ_ANALOG_READ: u8 = 12;
-pub const RQ_ADC_DAC_STROKE: u8 = 13;
-pub const RQ_PWM_SET_FREQ: u8 = 14;
-pub const RQ_PWM_SET_VALUE: u8 = 15;
-
 pub const STACK_SIZE: usize = 1024 * 64;
+/// Longest gap allowed between two bytes of a frame before it is discarded, until the host
+/// picks another with `RQ_SET_INTER_BYTE_TIMEOUT`
+pub const INTER_BYTE_TIMEOUT_MS: u16 = 100;
+/// Serial errors in a row after which reopening the port is considered futile
+pub const MAX_CONSECUTIVE_SERIAL_ERRORS: u32 = 8;
+/// Software transmit buffer of the host connection, stream blocks queue up in it while the UART
+/// sends the previous ones. Writes wait once it is full.
+pub const SERIAL_TX_BUFFER_SIZE: usize = 2048;
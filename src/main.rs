@@ -1,25 +1,41 @@
-mod com;
+mod adc;
+mod board;
 mod consts;
 mod neopixel;
+mod outputs;
 mod pins;
+mod registers;
+mod self_test;
+mod serial;
+mod settings;
+mod stream;
 
-use crate::com::{DigitalPort, Request, Response};
+use crate::adc::OneshotAdc;
 use crate::neopixel::{Neopixel, Rgb};
+use crate::outputs::{AnalogOutputs, PinsPwm, PwmOutput, ANALOG_WRITE_MAX, ANALOG_WRITE_PORTS};
 use crate::pins::{
-    ASidePinDrivers, BSidePinDrivers, PinDriversAnalogA, PinDriversDigitalA, PinDriversDigitalB,
-    PinsA, PinsB,
+    ASidePinDrivers, BSidePinDrivers, PinDriversAnalogA, PinDriversDip, PinsA, PinsB, PinsDip,
+    ANALOG_READ_CHANNELS, ANALOG_READ_PORTS, DEFAULT_ATTENUATION, DIGITAL_A_PINS, DIGITAL_B_PINS,
+    DIP_SWITCHES,
 };
-use error_stack::{Result, ResultExt};
-use esp_idf_svc::hal::adc::oneshot::config::AdcChannelConfig;
-use esp_idf_svc::hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
-use esp_idf_svc::hal::adc::ADC1;
-use esp_idf_svc::hal::gpio::{Gpio16, Gpio17};
+use crate::registers;
+use crate::serial::{PinsSerial, Serial};
+use crate::settings::Settings;
+use crate::stream::AdcStream;
+use b32_protocol::{
+    AdcMode, AnalogWritePort, AvrRegister, Capabilities, CommunicationError, DigitalPort,
+    ErrorCode, ErrorMode, Instruction, PortRegisters, Request, Response, SampleStats,
+};
+use embassy_futures::select::{select, Either};
+use error_stack::{Report, Result, ResultExt};
+use esp_idf_svc::hal::adc::attenuation::adc_atten_t;
+use esp_idf_svc::hal::delay::Delay;
 use esp_idf_svc::hal::prelude::Peripherals;
 #[cfg(feature = "rt-embassy")]
 use esp_idf_svc::hal::task::block_on;
-use esp_idf_svc::hal::uart::config::{DataBits, FlowControl};
-use esp_idf_svc::hal::uart::{AsyncUartDriver, UartConfig, UartDriver};
-use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{EspError, ESP_ERR_NOT_SUPPORTED};
+use esp_idf_svc::timer::{EspAsyncTimer, EspTaskTimerService};
 use log::{error, warn};
 use thiserror::Error;
 #[cfg(feature = "log")]
@@ -32,16 +48,47 @@ compile_error!(
 #[cfg(all(feature = "rt-tokio", feature = "rt-embassy"))]
 compile_error!("Multiple async runtimes selected. Please select only one of the following features: rt-tokio, rt-embassy");
 
+/// Shown while waiting for instructions
+const IDLE_COLOR: Rgb = Rgb { r: 0, g: 64, b: 0 };
+/// Replaces [IDLE_COLOR] after a failed self-test until the next one passes
+const SELF_TEST_FAILED_COLOR: Rgb = Rgb { r: 64, g: 16, b: 0 };
+
 #[derive(Debug, Error)]
 pub enum B32Error {
     #[error("create runtime")]
     CreateRuntime,
     #[error("esp32 error")]
     Esp32Error,
+    /// A peripheral failed while serving a single request, the board keeps going
+    #[error("peripheral error")]
+    PeripheralError,
     #[error("communication error")]
     CommunicationError,
 }
 
+impl B32Error {
+    /// Transient errors come from the host connection and are cleared by reopening it.
+    /// [B32Error::PeripheralError] only fails the request, anything else ends the main loop.
+    fn is_transient(&self) -> bool {
+        matches!(self, B32Error::CommunicationError)
+    }
+
+    /// Code reported to hosts that enabled [ErrorMode::Extended]
+    fn error_code(report: &Report<Self>) -> ErrorCode {
+        match report.downcast_ref::<CommunicationError>() {
+            Some(communication_error) => communication_error.error_code(),
+            // E.g. millivolt readings in AdcMode::B15 without calibration data
+            None if report
+                .downcast_ref::<EspError>()
+                .is_some_and(|err| err.code() == ESP_ERR_NOT_SUPPORTED) =>
+            {
+                ErrorCode::Unsupported
+            }
+            None => ErrorCode::PeripheralFailure,
+        }
+    }
+}
+
 fn main() -> error_stack::Result<(), B32Error> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
@@ -65,8 +112,20 @@ fn main() -> error_stack::Result<(), B32Error> {
         .change_context(B32Error::Esp32Error)?;
 
     //Analog pins
-    let adc = AdcDriver::new(peripherals.adc1).change_context(B32Error::Esp32Error)?;
-    let adc_channel_config = AdcChannelConfig::new();
+    let adc = OneshotAdc::new(peripherals.adc1).change_context(B32Error::Esp32Error)?;
+    let analog_outputs = AnalogOutputs::new(
+        peripherals.ledc.timer0,
+        peripherals.ledc.channel0,
+        peripherals.pins.gpio6,
+        peripherals.ledc.channel1,
+        peripherals.pins.gpio7,
+    )
+    .change_context(B32Error::Esp32Error)?;
+    let pins_pwm = PinsPwm {
+        timer: peripherals.ledc.timer1,
+        channel: peripherals.ledc.channel2,
+        pin: peripherals.pins.gpio10,
+    };
 
     let pins1 = PinsA {
         a0_ad: peripherals.pins.gpio2,
@@ -89,32 +148,29 @@ fn main() -> error_stack::Result<(), B32Error> {
         b7_d: peripherals.pins.gpio9,
     };
 
-    #[cfg(feature = "log")]
-    info!("Opening serial port...");
-    let usb_serial_config = UartConfig::new()
-        .data_bits(DataBits::DataBits8)
-        .parity_none()
-        .baudrate(Hertz(consts::BAUD))
-        .flow_control(FlowControl::None);
+    let pins_dip = PinsDip {
+        dip0: peripherals.pins.gpio11,
+    };
+
+    let pins_serial = PinsSerial {
+        uart: peripherals.uart1,
+        tx: peripherals.pins.gpio16,
+        rx: peripherals.pins.gpio17,
+    };
+
+    let nvs_partition = EspDefaultNvsPartition::take().change_context(B32Error::Esp32Error)?;
+    let settings = Settings::new(nvs_partition).change_context(B32Error::Esp32Error)?;
 
-    let mut usb_serial = AsyncUartDriver::new(
-        peripherals.uart1,
-        peripherals.pins.gpio16,
-        peripherals.pins.gpio17,
-        Option::<Gpio16>::None,
-        Option::<Gpio17>::None,
-        &usb_serial_config,
-    )
-    .change_context(B32Error::Esp32Error)?;
-    #[cfg(feature = "log")]
-    info!("Serial port opened");
     let runtime_fn = app_main(
-        &mut usb_serial,
+        pins_serial,
+        settings,
         &mut led,
         pins1,
         pins2,
+        pins_dip,
         adc,
-        &adc_channel_config,
+        analog_outputs,
+        pins_pwm,
     );
     #[cfg(feature = "rt-tokio")]
     let result = runtime.block_on(runtime_fn);
@@ -134,209 +190,834 @@ fn main() -> error_stack::Result<(), B32Error> {
     result
 }
 
+/// Configuration and drivers that outlive a single request
+struct BoardState<'d> {
+    settings: Settings,
+    a_side: ASidePinDrivers,
+    b_side: BSidePinDrivers,
+    registers_a: PortRegisters,
+    registers_b: PortRegisters,
+    dip_switch: PinDriversDip,
+    adc: OneshotAdc<'d>,
+    // Kept outside of the drivers so attenuations survive switching side A to digital
+    adc_attenuations: [adc_atten_t; ANALOG_READ_CHANNELS],
+    adc_mode: AdcMode,
+    analog_outputs: AnalogOutputs<'d>,
+    pwm: PwmOutput,
+    idle_color: Rgb,
+    error_mode: ErrorMode,
+    inter_byte_timeout_ms: u16,
+}
+
+impl BoardState<'_> {
+    /// Reads the emulated AVR register at `address`, or writes it if `value` is `Some`, through
+    /// the digital drivers of its side. `None` if no register is emulated at `address`.
+    fn avr_register(&mut self, address: u16, value: Option<u8>) -> Result<Option<u8>, B32Error> {
+        let Some(register) = AvrRegister::from_address(address) else {
+            warn!("No register emulated at {address:#06x}");
+            return Ok(None);
+        };
+        match register.port() {
+            DigitalPort::Port1 => {
+                let pins = self.a_side.digital(&self.registers_a);
+                pins.and_then(|pins| {
+                    registers::access(&mut self.registers_a, register, value, pins)
+                })
+            }
+            DigitalPort::Port2 => {
+                let pins = self.b_side.digital(&self.registers_b);
+                pins.and_then(|pins| {
+                    registers::access(&mut self.registers_b, register, value, pins)
+                })
+            }
+        }
+        .map(Some)
+        .change_context(B32Error::PeripheralError)
+    }
+}
+
 async fn app_main<'d>(
-    usb_serial: &mut AsyncUartDriver<'d, UartDriver<'d>>,
+    mut pins_serial: PinsSerial,
+    settings: Settings,
     led: &mut Neopixel,
-    mut pinsa: PinsA,
-    mut pinsb: PinsB,
-    adc: AdcDriver<'d, ADC1>,
-    adc_channel_config: &AdcChannelConfig,
+    pinsa: PinsA,
+    pinsb: PinsB,
+    pins_dip: PinsDip,
+    adc: OneshotAdc<'d>,
+    analog_outputs: AnalogOutputs<'d>,
+    pins_pwm: PinsPwm,
 ) -> error_stack::Result<(), B32Error> {
-    let mut a_side = ASidePinDrivers::None;
-    let mut b_side = BSidePinDrivers::None;
+    let adc_mode = settings.adc_mode().change_context(B32Error::Esp32Error)?;
+    let mut state = BoardState {
+        settings,
+        a_side: ASidePinDrivers::new(pinsa).change_context(B32Error::Esp32Error)?,
+        b_side: BSidePinDrivers::new(pinsb).change_context(B32Error::Esp32Error)?,
+        registers_a: PortRegisters::RESET,
+        registers_b: PortRegisters::RESET,
+        dip_switch: PinDriversDip::new(pins_dip).change_context(B32Error::Esp32Error)?,
+        adc,
+        adc_attenuations: [DEFAULT_ATTENUATION; ANALOG_READ_CHANNELS],
+        adc_mode,
+        analog_outputs,
+        pwm: PwmOutput::new(pins_pwm),
+        idle_color: IDLE_COLOR,
+        error_mode: ErrorMode::Legacy,
+        inter_byte_timeout_ms: consts::INTER_BYTE_TIMEOUT_MS,
+    };
+    // Instruction of the request in flight, reported along with its errors
+    let mut instruction;
+    // Request being served, its failures are answered in a shape the host can decode
+    let mut in_flight: Option<Request>;
+    let mut frame_timer = EspTaskTimerService::new()
+        .and_then(|timer_service| timer_service.timer_async())
+        .change_context(B32Error::Esp32Error)?;
+
+    #[cfg(feature = "log")]
+    info!("Opening serial port...");
+    let mut usb_serial = serial::open(&mut pins_serial).change_context(B32Error::Esp32Error)?;
+    #[cfg(feature = "log")]
+    info!("Serial port opened");
+    let mut serial_errors: u32 = 0;
+    let mut consecutive_serial_errors: u32 = 0;
 
     loop {
-        #[cfg(feature = "log")]
-        info!("Waiting for instructions...");
-        led.set_color(Rgb::new(0, 64, 0))
-            .change_context(B32Error::Esp32Error)?;
-        let request = com::read_request(usb_serial).await;
-        led.set_color(Rgb::new(0, 0, 128))
-            .change_context(B32Error::Esp32Error)?;
-        match request {
-            Ok(request) => {
-                #[cfg(feature = "log")]
-                debug!("Got request: {:?}", request);
-                match request {
-                    Some(Request::InitTest(value)) => {
-                        com::write_response(usb_serial, Response::TestEcho(value))
-                            .await
-                            .change_context(B32Error::Esp32Error)?;
-                    }
-                    Some(Request::AnalogRead(port)) => {
-                        let (output, analog_read) =
-                            if let ASidePinDrivers::Analog(mut analog_read) = a_side {
-                                (
-                                    analog_read
-                                        .analog_read(&adc, port)
-                                        .change_context(B32Error::Esp32Error)?,
-                                    ASidePinDrivers::Analog(analog_read),
-                                )
-                            } else {
-                                drop(a_side);
-                                let mut analog_read = PinDriversAnalogA {
-                                    a0_ad: AdcChannelDriver::new(
-                                        &adc,
-                                        &mut pinsa.a0_ad,
-                                        adc_channel_config,
-                                    )
-                                    .change_context(B32Error::Esp32Error)?,
-                                    a1_ad: AdcChannelDriver::new(
-                                        &adc,
-                                        &mut pinsa.a1_ad,
-                                        adc_channel_config,
-                                    )
-                                    .change_context(B32Error::Esp32Error)?,
-                                    a2_ad: AdcChannelDriver::new(
-                                        &adc,
-                                        &mut pinsa.a2_ad,
-                                        adc_channel_config,
-                                    )
-                                    .change_context(B32Error::Esp32Error)?,
-                                    a3_ad: AdcChannelDriver::new(
-                                        &adc,
-                                        &mut pinsa.a3_ad,
-                                        adc_channel_config,
-                                    )
-                                    .change_context(B32Error::Esp32Error)?,
-                                    a4_ad: AdcChannelDriver::new(
-                                        &adc,
-                                        &mut pinsa.a4_ad,
-                                        adc_channel_config,
-                                    )
-                                    .change_context(B32Error::Esp32Error)?,
-                                    a5_ad: AdcChannelDriver::new(
-                                        &adc,
-                                        &mut pinsa.a5_ad,
-                                        adc_channel_config,
-                                    )
-                                    .change_context(B32Error::Esp32Error)?,
-                                };
-                                (
-                                    analog_read
-                                        .analog_read(&adc, port)
-                                        .change_context(B32Error::Esp32Error)?,
-                                    ASidePinDrivers::Analog(analog_read),
-                                )
-                            };
-                        a_side = analog_read;
-                        com::write_response(usb_serial, Response::AnalogValue(output))
-                            .await
-                            .change_context(B32Error::Esp32Error)?;
-                    }
-                    Some(Request::DigitalRead(port)) => {
-                        let output = match port {
-                            DigitalPort::Port1 => {
-                                let (output, digital_read) =
-                                    if let ASidePinDrivers::Digital(mut digital_read) = a_side {
-                                        (
-                                            digital_read
-                                                .digital_read()
-                                                .change_context(B32Error::Esp32Error)?,
-                                            digital_read,
-                                        )
-                                    } else {
-                                        drop(a_side);
-                                        let mut digital_read = PinDriversDigitalA::new(&mut pinsa)
-                                            .change_context(B32Error::Esp32Error)?;
-                                        (
-                                            digital_read
-                                                .digital_read()
-                                                .change_context(B32Error::Esp32Error)?,
-                                            digital_read,
-                                        )
-                                    };
+        instruction = b32_protocol::consts::NO_INSTRUCTION;
+        in_flight = None;
+        let result = handle_request(
+            &mut state,
+            &mut usb_serial,
+            &mut frame_timer,
+            led,
+            &mut instruction,
+            &mut in_flight,
+        )
+        .await;
 
-                                a_side = ASidePinDrivers::Digital(digital_read);
-                                output
-                            }
-                            DigitalPort::Port2 => {
-                                let (output, digital_read) =
-                                    if let BSidePinDrivers::Digital(mut digital_read) = b_side {
-                                        (
-                                            digital_read
-                                                .digital_read()
-                                                .change_context(B32Error::Esp32Error)?,
-                                            digital_read,
-                                        )
-                                    } else {
-                                        drop(b_side);
-                                        let mut digital_read = PinDriversDigitalB::new(&mut pinsb)
-                                            .change_context(B32Error::Esp32Error)?;
-                                        (
-                                            digital_read
-                                                .digital_read()
-                                                .change_context(B32Error::Esp32Error)?,
-                                            digital_read,
-                                        )
-                                    };
+        let error_response = |err: &Report<B32Error>| {
+            let code = B32Error::error_code(err);
+            match &in_flight {
+                Some(request) => request.rejection(state.error_mode, code),
+                None => {
+                    let instruction = err
+                        .downcast_ref::<Instruction>()
+                        .map_or(instruction, |instruction| instruction.0);
+                    state.error_mode.response(code, instruction)
+                }
+            }
+        };
+        match result {
+            Ok(()) => consecutive_serial_errors = 0,
+            Err(err)
+                if err.current_context().is_transient()
+                    && consecutive_serial_errors < consts::MAX_CONSECUTIVE_SERIAL_ERRORS =>
+            {
+                serial_errors += 1;
+                consecutive_serial_errors += 1;
+                warn!(
+                    "Serial error {serial_errors} ({consecutive_serial_errors} in a row), reopening the port: {err:?}"
+                );
+                drop(usb_serial);
+                usb_serial = serial::open(&mut pins_serial).change_context(B32Error::Esp32Error)?;
+                // The host is most likely still waiting for an answer to its last request
+                if let Err(write_err) =
+                    b32_protocol::write_response(&mut usb_serial, error_response(&err)).await
+                {
+                    warn!("Could not report the serial error: {write_err:?}");
+                }
+            }
+            Err(err) if matches!(err.current_context(), B32Error::PeripheralError) => {
+                warn!("Request failed: {err:?}");
+                // A broken connection shows up again on the next read and is handled there
+                if let Err(write_err) =
+                    b32_protocol::write_response(&mut usb_serial, error_response(&err)).await
+                {
+                    warn!("Could not report the failed request: {write_err:?}");
+                }
+            }
+            Err(err) => {
+                // Best effort, the host should know the board is gone before the loop ends
+                let _ = b32_protocol::write_response(&mut usb_serial, error_response(&err)).await;
+                return Err(err);
+            }
+        }
+    }
+}
 
-                                b_side = BSidePinDrivers::Digital(digital_read);
-                                output
-                            }
-                        };
-                        com::write_response(usb_serial, Response::DigitalValue(output))
+/// Reads one request from the host and answers it
+///
+/// `instruction` and `in_flight` are set as soon as the request is known, the caller reports
+/// failures with them.
+async fn handle_request(
+    state: &mut BoardState<'_>,
+    usb_serial: &mut Serial<'_>,
+    frame_timer: &mut EspAsyncTimer,
+    led: &mut Neopixel,
+    instruction: &mut u8,
+    in_flight: &mut Option<Request>,
+) -> Result<(), B32Error> {
+    #[cfg(feature = "log")]
+    info!("Waiting for instructions...");
+    led.set_color(state.idle_color)
+        .change_context(B32Error::Esp32Error)?;
+    let request = b32_protocol::read_request_with_timeout(
+        usb_serial,
+        frame_timer,
+        u32::from(state.inter_byte_timeout_ms),
+    )
+    .await;
+    led.set_color(Rgb::new(0, 0, 128))
+        .change_context(B32Error::Esp32Error)?;
+    let request = match request {
+        Err(err) if matches!(err.current_context(), CommunicationError::Timeout) => {
+            warn!("Discarding partial frame: {err:?}");
+            let response = state.error_mode.response(
+                err.current_context().error_code(),
+                err.downcast_ref::<Instruction>()
+                    .map_or(*instruction, |instruction| instruction.0),
+            );
+            // Late bytes of the abandoned frame must not be taken for a new instruction
+            usb_serial
+                .driver()
+                .clear_rx()
+                .change_context(B32Error::CommunicationError)?;
+            b32_protocol::write_response(usb_serial, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+            return Ok(());
+        }
+        request => request.change_context(B32Error::CommunicationError)?,
+    };
+    #[cfg(feature = "log")]
+    debug!("Got request: {:?}", request);
+    if let Ok(request) = &request {
+        *instruction = request.instruction();
+        *in_flight = Some(request.clone());
+    }
+    // Set by requests that end with the reset of Request::Discard
+    let mut discard = false;
+    match request {
+        Ok(Request::Discard) => discard = true,
+        Ok(Request::InitTest(value)) => {
+            b32_protocol::write_response(usb_serial, Response::TestEcho(value))
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::Info) => {
+            let board_info = board::info().change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response(usb_serial, Response::Info(board_info))
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::IntTest(value)) => {
+            // Same transformation as the B15 firmware so existing hosts can verify it
+            let result = value.wrapping_mul(3);
+            b32_protocol::write_response_in_mode(
+                usb_serial,
+                state.error_mode,
+                Response::IntTestResult(result),
+            )
+            .await
+            .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SelfTest) => {
+            // The test sets both sides up itself
+            let a = state
+                .a_side
+                .self_test()
+                .change_context(B32Error::PeripheralError)?;
+            let b = state
+                .b_side
+                .self_test()
+                .change_context(B32Error::PeripheralError)?;
+            let (a_to_b, b_to_a) =
+                self_test::run(a, b).change_context(B32Error::PeripheralError)?;
+            // Later requests bring the sides back into their configuration
+            state
+                .a_side
+                .release()
+                .and_then(|()| state.b_side.release())
+                .change_context(B32Error::PeripheralError)?;
+            state.idle_color =
+                if a_to_b == self_test::LOOPBACK_MASK && b_to_a == self_test::LOOPBACK_MASK {
+                    IDLE_COLOR
+                } else {
+                    SELF_TEST_FAILED_COLOR
+                };
+            b32_protocol::write_response(usb_serial, Response::SelfTest { a_to_b, b_to_a })
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::ReadDipSwitch) => {
+            b32_protocol::write_response_in_mode(
+                usb_serial,
+                state.error_mode,
+                Response::DigitalValue(state.dip_switch.read()),
+            )
+            .await
+            .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::AnalogWrite(port, value)) => {
+            state
+                .analog_outputs
+                .analog_write(port, value)
+                .change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::AnalogRead(port)) => {
+            let response = if PinDriversAnalogA::is_available(port) {
+                let output = state
+                    .a_side
+                    .analog(&state.adc, &state.adc_attenuations)
+                    .change_context(B32Error::PeripheralError)?
+                    .analog_read(&state.adc, port, state.adc_mode)
+                    .change_context(B32Error::PeripheralError)?;
+                Response::AnalogValue(output)
+            } else {
+                warn!("Analog read port {port:?} is not available on this board");
+                Request::AnalogRead(port).rejection(state.error_mode, ErrorCode::Unsupported)
+            };
+            b32_protocol::write_response_in_mode(usb_serial, state.error_mode, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::AnalogReadAll) => {
+            let values = state
+                .a_side
+                .analog(&state.adc, &state.adc_attenuations)
+                .change_context(B32Error::PeripheralError)?
+                .analog_read_all(&state.adc, state.adc_mode)
+                .change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response(
+                usb_serial,
+                Response::AnalogScan {
+                    ports: ANALOG_READ_PORTS,
+                    values,
+                },
+            )
+            .await
+            .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::AnalogReadMillivolts(port)) => {
+            let response = if PinDriversAnalogA::is_available(port) {
+                let analog_read = state
+                    .a_side
+                    .analog(&state.adc, &state.adc_attenuations)
+                    .change_context(B32Error::PeripheralError)?;
+                if analog_read.is_calibrated(port) {
+                    let millivolts = analog_read
+                        .analog_read_mv(&state.adc, port)
+                        .change_context(B32Error::PeripheralError)?;
+                    Response::Millivolts(millivolts)
+                } else {
+                    warn!("Analog read port {port:?} has no calibration on this chip");
+                    state
+                        .error_mode
+                        .response(ErrorCode::Unsupported, *instruction)
+                }
+            } else {
+                warn!("Analog read port {port:?} is not available on this board");
+                state
+                    .error_mode
+                    .response(ErrorCode::Unsupported, *instruction)
+            };
+            b32_protocol::write_response(usb_serial, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetAttenuation(port, attenuation)) => {
+            let response = if PinDriversAnalogA::is_available(port) {
+                let attenuation = PinDriversAnalogA::adc_attenuation(attenuation);
+                state.adc_attenuations[port as usize] = attenuation;
+                // Drivers created later pick the attenuation up, active ones reconfigure
+                if let Some(analog) = state.a_side.active_analog() {
+                    analog
+                        .configure(&state.adc, port, attenuation)
+                        .change_context(B32Error::PeripheralError)?;
+                }
+                Response::Ok
+            } else {
+                warn!("Analog read port {port:?} is not available on this board");
+                state
+                    .error_mode
+                    .response(ErrorCode::Unsupported, *instruction)
+            };
+            b32_protocol::write_response(usb_serial, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::FullScale(port)) => {
+            let response = if PinDriversAnalogA::is_available(port) {
+                let analog_read = state
+                    .a_side
+                    .analog(&state.adc, &state.adc_attenuations)
+                    .change_context(B32Error::PeripheralError)?;
+                if analog_read.is_calibrated(port) {
+                    let millivolts = analog_read
+                        .full_scale_mv(port)
+                        .change_context(B32Error::PeripheralError)?;
+                    Response::Millivolts(millivolts)
+                } else {
+                    warn!("Analog read port {port:?} has no calibration on this chip");
+                    state
+                        .error_mode
+                        .response(ErrorCode::Unsupported, *instruction)
+                }
+            } else {
+                warn!("Analog read port {port:?} is not available on this board");
+                state
+                    .error_mode
+                    .response(ErrorCode::Unsupported, *instruction)
+            };
+            b32_protocol::write_response(usb_serial, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::AnalogReadStats {
+            port,
+            samples,
+            interval_us,
+        }) => {
+            let response = if PinDriversAnalogA::is_available(port) {
+                let analog_read = state
+                    .a_side
+                    .analog(&state.adc, &state.adc_attenuations)
+                    .change_context(B32Error::PeripheralError)?;
+                let delay = Delay::new_default();
+                let mut stats = SampleStats::default();
+                for sample in 0..samples {
+                    if sample > 0 && interval_us > 0 {
+                        delay.delay_us(interval_us as u32);
+                    }
+                    stats.push(
+                        analog_read
+                            .analog_read(&state.adc, port, state.adc_mode)
+                            .change_context(B32Error::PeripheralError)?,
+                    );
+                }
+                // The decoder rejects a sample count of 0
+                stats.finish().map_or_else(
+                    || {
+                        state
+                            .error_mode
+                            .response(ErrorCode::InvalidArgument, *instruction)
+                    },
+                    Response::AnalogStats,
+                )
+            } else {
+                warn!("Analog read port {port:?} is not available on this board");
+                state
+                    .error_mode
+                    .response(ErrorCode::Unsupported, *instruction)
+            };
+            b32_protocol::write_response(usb_serial, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::StreamStart { ports, sample_rate }) => {
+            if ports & !ANALOG_READ_PORTS != 0 {
+                warn!("Analog read ports {ports:#010b} are not available on this board");
+                b32_protocol::write_response(
+                    usb_serial,
+                    state
+                        .error_mode
+                        .response(ErrorCode::Unsupported, *instruction),
+                )
+                .await
+                .change_context(B32Error::CommunicationError)?;
+            } else {
+                // The oneshot drivers put the pins into analog mode
+                state
+                    .a_side
+                    .analog(&state.adc, &state.adc_attenuations)
+                    .change_context(B32Error::PeripheralError)?;
+                let mut stream = AdcStream::start(
+                    &mut state.adc,
+                    ports,
+                    sample_rate,
+                    state.a_side.adc_channels(),
+                    &state.adc_attenuations,
+                )
+                .change_context(B32Error::PeripheralError)?;
+                b32_protocol::write_response(usb_serial, Response::Ok)
+                    .await
+                    .change_context(B32Error::CommunicationError)?;
+                // A host that lost track of the stream resyncs with Discard
+                while !discard {
+                    let mut received = [0];
+                    match select(stream.read(), usb_serial.read(&mut received)).await {
+                        Either::First(samples) => {
+                            let samples = samples.change_context(B32Error::PeripheralError)?;
+                            // Not flushed, the ADC keeps converting while the UART sends. A slow
+                            // host stalls the queue until the ADC pool overflows and counts it.
+                            let dropped = stream.dropped();
+                            b32_protocol::queue_response(
+                                usb_serial,
+                                Response::StreamBlock { dropped, samples },
+                            )
                             .await
                             .change_context(B32Error::CommunicationError)?;
-                    }
-                    Some(Request::DigitalWrite(port, value)) => {
-                        match port {
-                            DigitalPort::Port1 => {
-                                a_side = if let ASidePinDrivers::Digital(mut digital_write) = a_side
-                                {
-                                    digital_write
-                                        .digital_write(value)
-                                        .change_context(B32Error::Esp32Error)?;
-                                    ASidePinDrivers::Digital(digital_write)
-                                } else {
-                                    drop(a_side);
-                                    let mut digital_write = PinDriversDigitalA::new(&mut pinsa)
-                                        .change_context(B32Error::Esp32Error)?;
-                                    digital_write
-                                        .digital_write(value)
-                                        .change_context(B32Error::Esp32Error)?;
-                                    ASidePinDrivers::Digital(digital_write)
-                                }
-                            }
-                            DigitalPort::Port2 => {
-                                b_side = if let BSidePinDrivers::Digital(mut digital_write) = b_side
-                                {
-                                    digital_write
-                                        .digital_write(value)
-                                        .change_context(B32Error::Esp32Error)?;
-                                    BSidePinDrivers::Digital(digital_write)
-                                } else {
-                                    drop(b_side);
-                                    let mut digital_write = PinDriversDigitalB::new(&mut pinsb)
-                                        .change_context(B32Error::Esp32Error)?;
-                                    digital_write
-                                        .digital_write(value)
-                                        .change_context(B32Error::Esp32Error)?;
-                                    BSidePinDrivers::Digital(digital_write)
+                        }
+                        Either::Second(read) => {
+                            read.change_context(B32Error::CommunicationError)?;
+                            match received[0] {
+                                b32_protocol::consts::RQ_STREAM_STOP => break,
+                                b32_protocol::consts::RQ_DISCARD => discard = true,
+                                _ => {
+                                    warn!("Ignoring instruction {:#x} while streaming", received[0])
                                 }
                             }
                         }
-
-                        com::write_response(usb_serial, Response::Ok)
-                            .await
-                            .change_context(B32Error::Esp32Error)?;
                     }
-                    _ => {
-                        warn!(
-                            "Unknown or malformed request received therefore responding with error"
-                        );
-                        com::write_response(usb_serial, Response::Error)
-                            .await
-                            .change_context(B32Error::Esp32Error)?;
+                }
+                let dropped = stream.dropped();
+                // Releases the ADC for oneshot reads before the host may send them
+                drop(stream);
+                if dropped > 0 {
+                    warn!("Stream dropped {dropped} samples");
+                }
+                // Discard answers for itself below
+                if !discard {
+                    b32_protocol::write_response(usb_serial, Response::StreamStopped { dropped })
+                        .await
+                        .change_context(B32Error::CommunicationError)?;
+                }
+            }
+        }
+        Ok(Request::StreamStop) => {
+            // Not streaming, nothing was dropped
+            b32_protocol::write_response(usb_serial, Response::StreamStopped { dropped: 0 })
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::AdcDacStroke {
+            write_port,
+            read_port,
+            steps,
+            settle_time_us,
+        }) => {
+            let response = if PinDriversAnalogA::is_available(read_port) {
+                let analog_read = state
+                    .a_side
+                    .analog(&state.adc, &state.adc_attenuations)
+                    .change_context(B32Error::PeripheralError)?;
+                let delay = Delay::new_default();
+                let mut samples = Vec::with_capacity(steps as usize);
+                for step in 0..steps {
+                    let value = if steps > 1 {
+                        (step as u32 * ANALOG_WRITE_MAX as u32 / (steps as u32 - 1)) as u16
+                    } else {
+                        0
+                    };
+                    state
+                        .analog_outputs
+                        .analog_write(write_port, value)
+                        .change_context(B32Error::PeripheralError)?;
+                    delay.delay_us(settle_time_us as u32);
+                    samples.push(
+                        analog_read
+                            .analog_read(&state.adc, read_port, state.adc_mode)
+                            .change_context(B32Error::PeripheralError)?,
+                    );
+                }
+                Response::AnalogValues(samples)
+            } else {
+                warn!("Analog read port {read_port:?} is not available on this board");
+                let request = Request::AdcDacStroke {
+                    write_port,
+                    read_port,
+                    steps,
+                    settle_time_us,
+                };
+                request.rejection(state.error_mode, ErrorCode::Unsupported)
+            };
+            b32_protocol::write_response_in_mode(usb_serial, state.error_mode, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::PwmSetFrequency(frequency)) => {
+            // The resolution depends on the frequency so the timer is set up again
+            state.pwm.stop().change_context(B32Error::PeripheralError)?;
+            let response = if frequency == 0 {
+                Response::PwmFrequency {
+                    frequency: 0,
+                    top: 0,
+                }
+            } else {
+                match state.pwm.start(frequency, state.analog_outputs.clock()) {
+                    Ok(top) => Response::PwmFrequency {
+                        frequency: state.pwm.frequency(),
+                        top,
+                    },
+                    Err(err) => {
+                        // The output stays off, the host can try another frequency
+                        warn!("LEDC rejected a PWM frequency of {frequency} Hz: {err:?}");
+                        state
+                            .error_mode
+                            .response(ErrorCode::PeripheralFailure, *instruction)
                     }
                 }
+            };
+            b32_protocol::write_response(usb_serial, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::PwmSetValue(value)) => {
+            let response = if state.pwm.top().is_some() {
+                state
+                    .pwm
+                    .set_value(value)
+                    .change_context(B32Error::PeripheralError)?;
+                Response::Ok
+            } else {
+                warn!("PWM value set before a frequency was configured");
+                state
+                    .error_mode
+                    .response(ErrorCode::InvalidArgument, *instruction)
+            };
+            b32_protocol::write_response(usb_serial, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::DigitalRead(port)) => {
+            let output = match port {
+                DigitalPort::Port1 => state
+                    .a_side
+                    .digital(&state.registers_a)
+                    .and_then(|pins| pins.digital_read()),
+                DigitalPort::Port2 => state
+                    .b_side
+                    .digital(&state.registers_b)
+                    .and_then(|pins| pins.digital_read()),
             }
-            Err(err) => {
-                com::write_response(usb_serial, Response::Error)
-                    .await
-                    .change_context(B32Error::Esp32Error)?;
-                return Err(err.change_context(B32Error::CommunicationError));
+            .change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response_in_mode(
+                usb_serial,
+                state.error_mode,
+                Response::DigitalValue(output),
+            )
+            .await
+            .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::DigitalWrite(port, value)) => {
+            match port {
+                DigitalPort::Port1 => {
+                    state.registers_a.output = value;
+                    state
+                        .a_side
+                        .digital(&state.registers_a)
+                        .and_then(|pins| pins.digital_write(value))
+                }
+                DigitalPort::Port2 => {
+                    state.registers_b.output = value;
+                    state
+                        .b_side
+                        .digital(&state.registers_b)
+                        .and_then(|pins| pins.digital_write(value))
+                }
+            }
+            .change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::DigitalUpdate(port, update)) => {
+            // Based on the levels last written rather than read, reading would copy the
+            // inputs into the outputs. Nothing else runs between the update and the write.
+            match port {
+                DigitalPort::Port1 => {
+                    state.registers_a.output = update.apply(state.registers_a.output);
+                    state
+                        .a_side
+                        .digital(&state.registers_a)
+                        .and_then(|pins| pins.digital_write(state.registers_a.output))
+                }
+                DigitalPort::Port2 => {
+                    state.registers_b.output = update.apply(state.registers_b.output);
+                    state
+                        .b_side
+                        .digital(&state.registers_b)
+                        .and_then(|pins| pins.digital_write(state.registers_b.output))
+                }
+            }
+            .change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetDirection(port, outputs)) => {
+            // Kept for drivers created later, e.g. after side A was in analog mode
+            match port {
+                DigitalPort::Port1 => {
+                    state.registers_a.direction = outputs;
+                    state
+                        .a_side
+                        .digital(&state.registers_a)
+                        .and_then(|pins| pins.set_direction(outputs))
+                }
+                DigitalPort::Port2 => {
+                    state.registers_b.direction = outputs;
+                    state
+                        .b_side
+                        .digital(&state.registers_b)
+                        .and_then(|pins| pins.set_direction(outputs))
+                }
             }
+            .change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetPull {
+            port,
+            pull_up,
+            pull_down,
+        }) => {
+            // Kept for drivers created later, dropping the drivers resets the pins
+            match port {
+                DigitalPort::Port1 => {
+                    state.registers_a.pull_up = pull_up;
+                    state.registers_a.pull_down = pull_down;
+                    state
+                        .a_side
+                        .digital(&state.registers_a)
+                        .and_then(|pins| pins.set_pulls(pull_up, pull_down))
+                }
+                DigitalPort::Port2 => {
+                    state.registers_b.pull_up = pull_up;
+                    state.registers_b.pull_down = pull_down;
+                    state
+                        .b_side
+                        .digital(&state.registers_b)
+                        .and_then(|pins| pins.set_pulls(pull_up, pull_down))
+                }
+            }
+            .change_context(B32Error::PeripheralError)?;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetMem8 { address, value }) => {
+            let response = match state.avr_register(address, Some(value))? {
+                Some(value) => Response::Memory8(value),
+                None => Request::SetMem8 { address, value }
+                    .rejection(state.error_mode, ErrorCode::Unsupported),
+            };
+            b32_protocol::write_response_in_mode(usb_serial, state.error_mode, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::GetMem8 { address }) => {
+            let response = match state.avr_register(address, None)? {
+                Some(value) => Response::Memory8(value),
+                None => {
+                    Request::GetMem8 { address }.rejection(state.error_mode, ErrorCode::Unsupported)
+                }
+            };
+            b32_protocol::write_response_in_mode(usb_serial, state.error_mode, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetMem16 { address, value }) => {
+            // Low byte first, like the 16 bit registers of the AVR
+            let [low, high] = value.to_le_bytes();
+            let low = state.avr_register(address, Some(low))?;
+            let high = state.avr_register(address.wrapping_add(1), Some(high))?;
+            let response = match low.zip(high) {
+                Some((low, high)) => Response::Memory16(u16::from_le_bytes([low, high])),
+                None => Request::SetMem16 { address, value }
+                    .rejection(state.error_mode, ErrorCode::Unsupported),
+            };
+            b32_protocol::write_response_in_mode(usb_serial, state.error_mode, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::GetMem16 { address }) => {
+            let low = state.avr_register(address, None)?;
+            let high = state.avr_register(address.wrapping_add(1), None)?;
+            let response = match low.zip(high) {
+                Some((low, high)) => Response::Memory16(u16::from_le_bytes([low, high])),
+                None => Request::GetMem16 { address }
+                    .rejection(state.error_mode, ErrorCode::Unsupported),
+            };
+            b32_protocol::write_response_in_mode(usb_serial, state.error_mode, response)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::Capabilities) => {
+            let capabilities = Capabilities {
+                analog_read_ports: ANALOG_READ_PORTS,
+                analog_write_ports: ANALOG_WRITE_PORTS,
+                digital_port1_pins: DIGITAL_A_PINS,
+                digital_port2_pins: DIGITAL_B_PINS,
+                dip_switches: DIP_SWITCHES,
+            };
+            b32_protocol::write_response(usb_serial, Response::Capabilities(capabilities))
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetErrorMode(mode)) => {
+            state.error_mode = mode;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetInterByteTimeout(timeout_ms)) => {
+            state.inter_byte_timeout_ms = timeout_ms;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Ok(Request::SetAdcMode(mode)) => {
+            state
+                .settings
+                .set_adc_mode(mode)
+                .change_context(B32Error::PeripheralError)?;
+            state.adc_mode = mode;
+            b32_protocol::write_response(usb_serial, Response::Ok)
+                .await
+                .change_context(B32Error::CommunicationError)?;
+        }
+        Err(invalid) => {
+            warn!("Unknown or malformed request received therefore responding with error");
+            b32_protocol::write_response(
+                usb_serial,
+                state.error_mode.response(invalid.code, invalid.instruction),
+            )
+            .await
+            .change_context(B32Error::CommunicationError)?;
+        }
+    }
+    if discard {
+        // Release all pins so both sides fall back to their reset state
+        state
+            .a_side
+            .release()
+            .and_then(|()| state.b_side.release())
+            .change_context(B32Error::PeripheralError)?;
+        state.registers_a = PortRegisters::RESET;
+        state.registers_b = PortRegisters::RESET;
+        state.adc_attenuations = [DEFAULT_ATTENUATION; ANALOG_READ_CHANNELS];
+        state.pwm.stop().change_context(B32Error::PeripheralError)?;
+        for port in [AnalogWritePort::Port1, AnalogWritePort::Port2] {
+            state
+                .analog_outputs
+                .analog_write(port, 0)
+                .change_context(B32Error::PeripheralError)?;
         }
+        state.idle_color = IDLE_COLOR;
+        // A new session might be started by a B15 host
+        state.error_mode = ErrorMode::Legacy;
+        state.inter_byte_timeout_ms = consts::INTER_BYTE_TIMEOUT_MS;
+        // Whatever is still buffered belongs to an abandoned frame
+        usb_serial
+            .driver()
+            .clear_rx()
+            .change_context(B32Error::CommunicationError)?;
+        b32_protocol::write_response(usb_serial, Response::Ok)
+            .await
+            .change_context(B32Error::CommunicationError)?;
     }
+    Ok(())
 }
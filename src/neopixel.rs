@@ -54,6 +54,7 @@ impl Neopixel {
 #[error("set neopixel color")]
 pub struct SetNeopixelColorError;
 
+#[derive(Debug, Clone, Copy)]
 pub struct Rgb {
     pub r: u8,
     pub g: u8,
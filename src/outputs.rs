@@ -0,0 +1,185 @@
+//! Analog and PWM outputs
+//!
+//! The ESP32-C6 has no DAC, so the analog write ports output a 78.125 kHz PWM signal
+//! generated by the LEDC peripheral which needs an external RC low pass
+//! (e.g. 10 kΩ / 1 µF) to become a DC voltage.
+//! Values are interpreted like on the 10 bit DAC of the B15:
+//! `V_out = 3.3 V * min(value, 1023) / 1023`, values above 1023 are clamped to full scale.
+//!
+//! The PWM output uses its own timer so its frequency can be changed independently.
+
+use b32_protocol::AnalogWritePort;
+use esp_idf_svc::hal::gpio::{Gpio10, Gpio6, Gpio7, Pin};
+use esp_idf_svc::hal::ledc::config::TimerConfig;
+use esp_idf_svc::hal::ledc::{
+    LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver, Resolution, CHANNEL0, CHANNEL1, CHANNEL2,
+    TIMER0, TIMER1,
+};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::sys::{
+    esp, ledc_channel_config, ledc_channel_config_t, ledc_get_freq,
+    ledc_intr_type_t_LEDC_INTR_DISABLE, ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_set_duty_and_update,
+    ledc_stop, ledc_timer_config, ledc_timer_config_t, ledc_timer_rst,
+    soc_periph_ledc_clk_src_legacy_t_LEDC_AUTO_CLK, EspError, ESP_ERR_INVALID_STATE,
+};
+use log::warn;
+
+/// Value that results in the full supply voltage
+pub const ANALOG_WRITE_MAX: u16 = 1023;
+/// Both analog write ports are backed by a LEDC channel, bit n is port n + 1
+pub const ANALOG_WRITE_PORTS: u8 = 0b11;
+
+/// Duty resolution of the analog write ports
+const ANALOG_WRITE_BITS: u32 = 10;
+/// The 80 MHz PLL clock divided by the 10 bit duty resolution, no other clock source reaches
+/// it so the automatic clock selection settles on the PLL clock
+const ANALOG_WRITE_FREQUENCY: Hertz = Hertz(78_125);
+
+pub struct AnalogOutputs<'d> {
+    // The timer must outlive the channels, it is reset when dropped
+    timer: LedcTimerDriver<'d, TIMER0>,
+    out1: LedcDriver<'d>,
+    out2: LedcDriver<'d>,
+}
+
+impl<'d> AnalogOutputs<'d> {
+    pub fn new(
+        timer: TIMER0,
+        out1_channel: CHANNEL0,
+        out1_pin: Gpio6,
+        out2_channel: CHANNEL1,
+        out2_pin: Gpio7,
+    ) -> Result<Self, EspError> {
+        let timer_config = TimerConfig::new()
+            .frequency(ANALOG_WRITE_FREQUENCY)
+            .resolution(Resolution::Bits10);
+        let timer = LedcTimerDriver::new(timer, &timer_config)?;
+        let out1 = LedcDriver::new(out1_channel, &timer, out1_pin)?;
+        let out2 = LedcDriver::new(out2_channel, &timer, out2_pin)?;
+
+        Ok(Self { timer, out1, out2 })
+    }
+
+    pub fn analog_write(&mut self, port: AnalogWritePort, value: u16) -> Result<(), EspError> {
+        let output = match port {
+            AnalogWritePort::Port1 => &mut self.out1,
+            AnalogWritePort::Port2 => &mut self.out2,
+        };
+        let value = value.min(ANALOG_WRITE_MAX) as u32;
+        let duty = value * output.get_max_duty() / ANALOG_WRITE_MAX as u32;
+        output.set_duty(duty)
+    }
+
+    /// Clock the LEDC timers count with, all low speed timers share the one the analog outputs
+    /// selected
+    pub fn clock(&self) -> u32 {
+        let frequency =
+            unsafe { ledc_get_freq(ledc_mode_t_LEDC_LOW_SPEED_MODE, self.timer.timer()) };
+        frequency << ANALOG_WRITE_BITS
+    }
+}
+
+pub struct PinsPwm {
+    pub timer: TIMER1,
+    pub channel: CHANNEL2,
+    pub pin: Gpio10,
+}
+
+/// PWM output on its own timer, configured with ESP-IDF directly as the frequency sets the
+/// resolution and the drivers of the hal could only be created again from borrowed pins
+pub struct PwmOutput {
+    // Keeps anything else off the timer, channel and pin
+    pins: PinsPwm,
+    /// Duty resolution in bits while the output runs
+    bits: Option<u32>,
+}
+
+impl PwmOutput {
+    /// The output stays off until [Self::start]
+    pub fn new(pins: PinsPwm) -> Self {
+        Self { pins, bits: None }
+    }
+
+    /// Starts the PWM output with 0% duty and the highest resolution `clock` allows at
+    /// `frequency`, returns the top
+    pub fn start(&mut self, frequency: u32, clock: u32) -> Result<u16, EspError> {
+        self.stop()?;
+        let bits = resolution_bits(frequency, clock);
+        let timer_config = ledc_timer_config_t {
+            speed_mode: ledc_mode_t_LEDC_LOW_SPEED_MODE,
+            timer_num: TIMER1::timer(),
+            duty_resolution: bits,
+            freq_hz: frequency,
+            clk_cfg: soc_periph_ledc_clk_src_legacy_t_LEDC_AUTO_CLK,
+            deconfigure: false,
+        };
+        esp!(unsafe { ledc_timer_config(&timer_config) })?;
+        let channel_config = ledc_channel_config_t {
+            speed_mode: ledc_mode_t_LEDC_LOW_SPEED_MODE,
+            channel: CHANNEL2::channel(),
+            timer_sel: TIMER1::timer(),
+            intr_type: ledc_intr_type_t_LEDC_INTR_DISABLE,
+            gpio_num: self.pins.pin.pin(),
+            duty: 0,
+            hpoint: 0,
+            ..Default::default()
+        };
+        esp!(unsafe { ledc_channel_config(&channel_config) })?;
+        self.bits = Some(bits);
+        Ok(self.top().unwrap_or_default())
+    }
+
+    /// Holds the pin low and resets the timer, the output has to be started again
+    pub fn stop(&mut self) -> Result<(), EspError> {
+        if self.bits.take().is_some() {
+            esp!(unsafe { ledc_stop(ledc_mode_t_LEDC_LOW_SPEED_MODE, CHANNEL2::channel(), 0) })?;
+            esp!(unsafe { ledc_timer_rst(ledc_mode_t_LEDC_LOW_SPEED_MODE, TIMER1::timer()) })?;
+        }
+        Ok(())
+    }
+
+    /// The frequency after rounding to what the clock divider can achieve
+    pub fn frequency(&self) -> u32 {
+        unsafe { ledc_get_freq(ledc_mode_t_LEDC_LOW_SPEED_MODE, TIMER1::timer()) }
+    }
+
+    /// Value that results in 100% duty, `None` while the output is stopped
+    pub fn top(&self) -> Option<u16> {
+        self.bits.map(|bits| ((1u32 << bits) - 1) as u16)
+    }
+
+    /// Sets the duty to `value / top`, values above top are clamped
+    pub fn set_value(&mut self, value: u16) -> Result<(), EspError> {
+        let top = self
+            .top()
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+        let duty = value.min(top);
+        esp!(unsafe {
+            ledc_set_duty_and_update(
+                ledc_mode_t_LEDC_LOW_SPEED_MODE,
+                CHANNEL2::channel(),
+                duty as u32,
+                0,
+            )
+        })
+    }
+}
+
+impl Drop for PwmOutput {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            warn!("Could not stop the PWM output: {err:?}");
+        }
+    }
+}
+
+/// Highest duty resolution a timer counting with `clock` supports at `frequency`, capped at the
+/// 14 bits the hal offers on the ESP32-C6
+fn resolution_bits(frequency: u32, clock: u32) -> u32 {
+    const MAX_BITS: u32 = 14;
+    let ticks_per_period = clock / frequency.max(1);
+    ticks_per_period
+        .checked_ilog2()
+        .unwrap_or(0)
+        .clamp(1, MAX_BITS)
+}
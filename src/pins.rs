@@ -1,8 +1,34 @@
-use crate::com::AnalogReadPort;
-use esp_idf_svc::hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
-use esp_idf_svc::hal::adc::ADC1;
+use crate::adc::{Calibration, OneshotAdc};
+use crate::registers::apply;
+use b32_protocol::{
+    pack_levels, unpack_levels, AdcMode, AnalogReadPort, Attenuation, PortRegisters,
+};
+use esp_idf_svc::hal::adc::attenuation::{self, adc_atten_t};
 use esp_idf_svc::hal::gpio::*;
-use esp_idf_svc::sys::EspError;
+use esp_idf_svc::sys::{
+    adc_channel_t, esp, gpio_config, gpio_config_t, gpio_int_type_t_GPIO_INTR_DISABLE,
+    gpio_mode_t_GPIO_MODE_DISABLE, gpio_mode_t_GPIO_MODE_INPUT, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT,
+    gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD, gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+    gpio_pullup_t_GPIO_PULLUP_DISABLE, gpio_set_direction, EspError, ESP_ERR_NOT_SUPPORTED,
+};
+
+/// Pins of [PinsA] in the bit order of `digital_write`, a7_d is bit 6 as side A has no a6
+pub const DIGITAL_A_PINS: u8 = 0x7F;
+/// Pins of [PinsB] in the bit order of `digital_write`
+pub const DIGITAL_B_PINS: u8 = 0xFF;
+/// Analog read ports with an ADC channel, bit n is `AnalogReadPort` n + 1.
+/// ADC1 has only 7 channels and GPIO6 is taken by the first analog output.
+pub const ANALOG_READ_PORTS: u8 = 0b0011_1111;
+/// Number of channels of [PinDriversAnalogA], one attenuation per channel
+pub const ANALOG_READ_CHANNELS: usize = 6;
+/// Attenuation of the analog read ports until the host changes it
+pub const DEFAULT_ATTENUATION: adc_atten_t = attenuation::NONE;
+/// Highest raw value of the 12 bit ADC
+const ADC_MAX_RAW: u16 = 4095;
+/// Full scale of [AdcMode::B15] readings
+const B15_ADC_MAX: u32 = 1023;
+/// Voltage the B15 ADC reaches its full scale at
+const B15_ADC_REFERENCE_MV: u32 = 5000;
 
 pub struct PinsA {
     // Side A
@@ -15,27 +41,42 @@ pub struct PinsA {
     pub a7_d: Gpio14,
 }
 
-pub struct PinDriversDigitalA<'p> {
+impl PinsA {
+    /// ADC1 channels of the analog capable pins, indexed like `AnalogReadPort`
+    pub fn adc_channels(&self) -> [adc_channel_t; ANALOG_READ_CHANNELS] {
+        [
+            self.a0_ad.adc_channel(),
+            self.a1_ad.adc_channel(),
+            self.a2_ad.adc_channel(),
+            self.a3_ad.adc_channel(),
+            self.a4_ad.adc_channel(),
+            self.a5_ad.adc_channel(),
+        ]
+    }
+}
+
+pub struct PinDriversDigitalA {
     //Side A
     //TODO: add support for ADC channels
-    pub a0_ad: PinDriver<'p, Gpio2, InputOutput>,
-    pub a1_ad: PinDriver<'p, Gpio3, InputOutput>,
-    pub a2_ad: PinDriver<'p, Gpio4, InputOutput>,
-    pub a3_ad: PinDriver<'p, Gpio5, InputOutput>,
-    pub a4_ad: PinDriver<'p, Gpio0, InputOutput>,
-    pub a5_ad: PinDriver<'p, Gpio1, InputOutput>,
-    pub a7_d: PinDriver<'p, Gpio14, InputOutput>,
-}
-
-impl<'p> PinDriversDigitalA<'p> {
-    pub fn new(pins: &'p mut PinsA) -> Result<Self, EspError> {
-        let a0_ad = PinDriver::input_output(&mut pins.a0_ad)?;
-        let a1_ad = PinDriver::input_output(&mut pins.a1_ad)?;
-        let a2_ad = PinDriver::input_output(&mut pins.a2_ad)?;
-        let a3_ad = PinDriver::input_output(&mut pins.a3_ad)?;
-        let a4_ad = PinDriver::input_output(&mut pins.a4_ad)?;
-        let a5_ad = PinDriver::input_output(&mut pins.a5_ad)?;
-        let a7_d = PinDriver::input_output(&mut pins.a7_d)?;
+    pub a0_ad: PinDriver<'static, Gpio2, InputOutput>,
+    pub a1_ad: PinDriver<'static, Gpio3, InputOutput>,
+    pub a2_ad: PinDriver<'static, Gpio4, InputOutput>,
+    pub a3_ad: PinDriver<'static, Gpio5, InputOutput>,
+    pub a4_ad: PinDriver<'static, Gpio0, InputOutput>,
+    pub a5_ad: PinDriver<'static, Gpio1, InputOutput>,
+    pub a7_d: PinDriver<'static, Gpio14, InputOutput>,
+}
+
+impl PinDriversDigitalA {
+    /// Takes the pins for good, the drivers are kept across mode switches rather than dropped
+    pub fn new(pins: PinsA) -> Result<Self, EspError> {
+        let a0_ad = PinDriver::input_output(pins.a0_ad)?;
+        let a1_ad = PinDriver::input_output(pins.a1_ad)?;
+        let a2_ad = PinDriver::input_output(pins.a2_ad)?;
+        let a3_ad = PinDriver::input_output(pins.a3_ad)?;
+        let a4_ad = PinDriver::input_output(pins.a4_ad)?;
+        let a5_ad = PinDriver::input_output(pins.a5_ad)?;
+        let a7_d = PinDriver::input_output(pins.a7_d)?;
 
         Ok(Self {
             a0_ad,
@@ -48,9 +89,9 @@ impl<'p> PinDriversDigitalA<'p> {
         })
     }
 
+    /// Levels of the pins in the bit order of [b32_protocol::pack_levels], a7_d is bit 6
     pub fn digital_read(&mut self) -> Result<u8, EspError> {
-        const POS_MASKS: [u8; 8] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80];
-        let bits: [bool; 7] = [
+        Ok(pack_levels(&[
             self.a0_ad.is_high(),
             self.a1_ad.is_high(),
             self.a2_ad.is_high(),
@@ -58,69 +99,310 @@ impl<'p> PinDriversDigitalA<'p> {
             self.a4_ad.is_high(),
             self.a5_ad.is_high(),
             self.a7_d.is_high(),
-        ];
-
-        let mut result = 0;
-        for (i, entry) in bits.iter().enumerate() {
-            if *entry {
-                result |= POS_MASKS[i];
-            }
-        }
-        let result = result.reverse_bits();
-        Ok(result)
+        ]))
     }
+
+    /// Same bit order as [Self::digital_read], bit 7 is ignored
     pub fn digital_write(&mut self, value: u8) -> Result<(), EspError> {
-        let mut levels = [Level::Low; 8];
-        for (i, entry) in levels.iter_mut().enumerate() {
-            *entry = if (value & (1 << i)) != 0 {
-                Level::High
-            } else {
-                Level::Low
-            };
+        let levels = unpack_levels::<7>(value);
+        self.a0_ad.set_level(levels[0].into())?;
+        self.a1_ad.set_level(levels[1].into())?;
+        self.a2_ad.set_level(levels[2].into())?;
+        self.a3_ad.set_level(levels[3].into())?;
+        self.a4_ad.set_level(levels[4].into())?;
+        self.a5_ad.set_level(levels[5].into())?;
+        self.a7_d.set_level(levels[6].into())?;
+        Ok(())
+    }
+
+    /// Pins with a set bit in `outputs` drive their level, the others are plain inputs
+    pub fn set_direction(&mut self, outputs: u8) -> Result<(), EspError> {
+        set_direction(self.a0_ad.pin(), outputs & 0x01 != 0)?;
+        set_direction(self.a1_ad.pin(), outputs & 0x02 != 0)?;
+        set_direction(self.a2_ad.pin(), outputs & 0x04 != 0)?;
+        set_direction(self.a3_ad.pin(), outputs & 0x08 != 0)?;
+        set_direction(self.a4_ad.pin(), outputs & 0x10 != 0)?;
+        set_direction(self.a5_ad.pin(), outputs & 0x20 != 0)?;
+        set_direction(self.a7_d.pin(), outputs & 0x40 != 0)?;
+        Ok(())
+    }
+
+    /// Enables the pull resistors of the pins with a set bit in `pull_up` and `pull_down`
+    pub fn set_pulls(&mut self, pull_up: u8, pull_down: u8) -> Result<(), EspError> {
+        self.a0_ad.set_pull(pull(pull_up, pull_down, 0))?;
+        self.a1_ad.set_pull(pull(pull_up, pull_down, 1))?;
+        self.a2_ad.set_pull(pull(pull_up, pull_down, 2))?;
+        self.a3_ad.set_pull(pull(pull_up, pull_down, 3))?;
+        self.a4_ad.set_pull(pull(pull_up, pull_down, 4))?;
+        self.a5_ad.set_pull(pull(pull_up, pull_down, 5))?;
+        self.a7_d.set_pull(pull(pull_up, pull_down, 6))?;
+        Ok(())
+    }
+
+    /// Turns all pins into open drain outputs, writing a high level releases the pin
+    pub fn set_open_drain(&mut self) -> Result<(), EspError> {
+        for pin in self.pins() {
+            set_open_drain(pin)?;
         }
-        self.a0_ad.set_level(levels[0])?;
-        self.a1_ad.set_level(levels[1])?;
-        self.a2_ad.set_level(levels[2])?;
-        self.a3_ad.set_level(levels[3])?;
-        self.a4_ad.set_level(levels[4])?;
-        self.a5_ad.set_level(levels[5])?;
-        self.a7_d.set_level(levels[6])?;
         Ok(())
     }
+
+    /// Disables the pins and their pulls, like dropping the drivers of the hal does
+    pub fn release(&mut self) -> Result<(), EspError> {
+        release(&self.pins())
+    }
+
+    fn pins(&self) -> [i32; 7] {
+        [
+            self.a0_ad.pin(),
+            self.a1_ad.pin(),
+            self.a2_ad.pin(),
+            self.a3_ad.pin(),
+            self.a4_ad.pin(),
+            self.a5_ad.pin(),
+            self.a7_d.pin(),
+        ]
+    }
 }
 
-pub struct PinDriversAnalogA<'p, 'd> {
-    //Side A
-    pub a0_ad: AdcChannelDriver<'d, Gpio2, &'p AdcDriver<'d, ADC1>>,
-    pub a1_ad: AdcChannelDriver<'d, Gpio3, &'p AdcDriver<'d, ADC1>>,
-    pub a2_ad: AdcChannelDriver<'d, Gpio4, &'p AdcDriver<'d, ADC1>>,
-    pub a3_ad: AdcChannelDriver<'d, Gpio5, &'p AdcDriver<'d, ADC1>>,
-    pub a4_ad: AdcChannelDriver<'d, Gpio0, &'p AdcDriver<'d, ADC1>>,
-    pub a5_ad: AdcChannelDriver<'d, Gpio1, &'p AdcDriver<'d, ADC1>>,
+/// ADC1 channel of an analog read port and its calibration at the configured attenuation
+struct AnalogChannel {
+    channel: adc_channel_t,
+    calibration: Option<Calibration>,
+}
+
+pub struct PinDriversAnalogA {
+    channels: [AnalogChannel; ANALOG_READ_CHANNELS],
 }
 
-impl<'p, 'd> PinDriversAnalogA<'p, 'd> {
+impl PinDriversAnalogA {
+    /// ESP-IDF switches the pads of `channels` to analog mode, [ASidePinDrivers] keeps the
+    /// digital drivers off them meanwhile
+    pub fn new(
+        adc: &OneshotAdc,
+        channels: &[adc_channel_t; ANALOG_READ_CHANNELS],
+        attenuations: &[adc_atten_t; ANALOG_READ_CHANNELS],
+    ) -> Result<Self, EspError> {
+        for (channel, attenuation) in channels.iter().zip(attenuations) {
+            adc.configure(*channel, *attenuation)?;
+        }
+        let channels = core::array::from_fn(|port| AnalogChannel {
+            channel: channels[port],
+            calibration: Calibration::new(channels[port], attenuations[port]),
+        });
+
+        Ok(Self { channels })
+    }
+
+    /// Converts `port` with `attenuation` from now on, the other channels are left alone
+    pub fn configure(
+        &mut self,
+        adc: &OneshotAdc,
+        port: AnalogReadPort,
+        attenuation: adc_atten_t,
+    ) -> Result<(), EspError> {
+        let channel = Self::channel_mut(&mut self.channels, port)?;
+        adc.configure(channel.channel, attenuation)?;
+        // Deletes the scheme of the previous attenuation
+        channel.calibration = Calibration::new(channel.channel, attenuation);
+        Ok(())
+    }
+
+    /// Whether millivolts can be read from `port`, chips without calibration data in their
+    /// eFuses only deliver raw values
+    pub fn is_calibrated(&self, port: AnalogReadPort) -> bool {
+        Self::channel(&self.channels, port).is_ok_and(|channel| channel.calibration.is_some())
+    }
+
+    /// Calibrated voltage of the highest raw value with the current attenuation of `port`
+    pub fn full_scale_mv(&self, port: AnalogReadPort) -> Result<u16, EspError> {
+        Self::calibration(&self.channels, port)?.millivolts(ADC_MAX_RAW)
+    }
+
+    /// Reads `port` in the scale selected by `mode`
     pub fn analog_read(
         &mut self,
-        adc: &'p AdcDriver<'d, ADC1>,
+        adc: &OneshotAdc,
         port: AnalogReadPort,
+        mode: AdcMode,
     ) -> Result<u16, EspError> {
-        match port {
-            AnalogReadPort::Port1 => adc.read(&mut self.a0_ad),
-            AnalogReadPort::Port2 => adc.read(&mut self.a1_ad),
-            AnalogReadPort::Port3 => adc.read(&mut self.a2_ad),
-            AnalogReadPort::Port4 => adc.read(&mut self.a3_ad),
-            AnalogReadPort::Port5 => adc.read(&mut self.a4_ad),
-            AnalogReadPort::Port6 => adc.read(&mut self.a5_ad),
-            AnalogReadPort::Port7 | AnalogReadPort::Port8 => Ok(0), //ADC does not have enough channels
+        match mode {
+            AdcMode::Native => self.analog_read_raw(adc, port),
+            AdcMode::B15 => {
+                let millivolts = self.analog_read_mv(adc, port)? as u32;
+                let value =
+                    (millivolts * B15_ADC_MAX + B15_ADC_REFERENCE_MV / 2) / B15_ADC_REFERENCE_MV;
+                Ok(value.min(B15_ADC_MAX) as u16)
+            }
         }
     }
+
+    /// Reads all ports of [ANALOG_READ_PORTS] back to back in port order
+    pub fn analog_read_all(
+        &mut self,
+        adc: &OneshotAdc,
+        mode: AdcMode,
+    ) -> Result<Vec<u16>, EspError> {
+        [
+            AnalogReadPort::Port1,
+            AnalogReadPort::Port2,
+            AnalogReadPort::Port3,
+            AnalogReadPort::Port4,
+            AnalogReadPort::Port5,
+            AnalogReadPort::Port6,
+        ]
+        .into_iter()
+        .map(|port| self.analog_read(adc, port, mode))
+        .collect()
+    }
+
+    pub fn analog_read_raw(
+        &mut self,
+        adc: &OneshotAdc,
+        port: AnalogReadPort,
+    ) -> Result<u16, EspError> {
+        adc.read_raw(Self::channel(&self.channels, port)?.channel)
+    }
+
+    /// Voltage at the pin corrected by the calibration of the chip, fails on uncalibrated ports
+    pub fn analog_read_mv(
+        &mut self,
+        adc: &OneshotAdc,
+        port: AnalogReadPort,
+    ) -> Result<u16, EspError> {
+        let calibration = Self::calibration(&self.channels, port)?;
+        let raw = adc.read_raw(Self::channel(&self.channels, port)?.channel)?;
+        calibration.millivolts(raw)
+    }
+
+    /// Whether `port` is backed by an ADC channel, reads of other ports fail
+    pub fn is_available(port: AnalogReadPort) -> bool {
+        ANALOG_READ_PORTS & (1 << port as u8) != 0
+    }
+
+    /// ESP-IDF setting of an [Attenuation] requested by the host
+    pub fn adc_attenuation(attenuation: Attenuation) -> adc_atten_t {
+        match attenuation {
+            Attenuation::Db0 => attenuation::NONE,
+            Attenuation::Db2_5 => attenuation::DB_2_5,
+            Attenuation::Db6 => attenuation::DB_6,
+            Attenuation::Db11 => attenuation::DB_11,
+        }
+    }
+
+    /// Channel of `port`, see [ANALOG_READ_PORTS] for the ports without one
+    fn channel(
+        channels: &[AnalogChannel; ANALOG_READ_CHANNELS],
+        port: AnalogReadPort,
+    ) -> Result<&AnalogChannel, EspError> {
+        channels
+            .get(port as usize)
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+    }
+
+    /// Calibration of `port`, raw values must not pass for millivolts without one
+    fn calibration(
+        channels: &[AnalogChannel; ANALOG_READ_CHANNELS],
+        port: AnalogReadPort,
+    ) -> Result<&Calibration, EspError> {
+        Self::channel(channels, port)?
+            .calibration
+            .as_ref()
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+    }
+
+    fn channel_mut(
+        channels: &mut [AnalogChannel; ANALOG_READ_CHANNELS],
+        port: AnalogReadPort,
+    ) -> Result<&mut AnalogChannel, EspError> {
+        channels
+            .get_mut(port as usize)
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+    }
+}
+
+/// Side A in digital or analog mode, switched as the requests need it
+pub struct ASidePinDrivers {
+    digital: PinDriversDigitalA,
+    channels: [adc_channel_t; ANALOG_READ_CHANNELS],
+    mode: ASideMode,
 }
 
-pub enum ASidePinDrivers<'p, 'd> {
-    None,
-    Digital(PinDriversDigitalA<'p>),
-    Analog(PinDriversAnalogA<'p, 'd>),
+enum ASideMode {
+    /// Pins disabled and floating like after reset
+    Released,
+    Digital,
+    Analog(PinDriversAnalogA),
+}
+
+impl ASidePinDrivers {
+    /// Starts with the pins released
+    pub fn new(pins: PinsA) -> Result<Self, EspError> {
+        let channels = pins.adc_channels();
+        let mut side = Self {
+            digital: PinDriversDigitalA::new(pins)?,
+            channels,
+            mode: ASideMode::Released,
+        };
+        side.release()?;
+        Ok(side)
+    }
+
+    /// Switches to digital mode if needed, the pins take the configuration in `registers`
+    pub fn digital(
+        &mut self,
+        registers: &PortRegisters,
+    ) -> Result<&mut PinDriversDigitalA, EspError> {
+        if !matches!(self.mode, ASideMode::Digital) {
+            self.release()?;
+            apply(registers, &mut self.digital)?;
+            self.mode = ASideMode::Digital;
+        }
+        Ok(&mut self.digital)
+    }
+
+    /// Switches to analog mode if needed, the channels take `attenuations`
+    pub fn analog(
+        &mut self,
+        adc: &OneshotAdc,
+        attenuations: &[adc_atten_t; ANALOG_READ_CHANNELS],
+    ) -> Result<&mut PinDriversAnalogA, EspError> {
+        if !matches!(self.mode, ASideMode::Analog(_)) {
+            self.release()?;
+            self.mode =
+                ASideMode::Analog(PinDriversAnalogA::new(adc, &self.channels, attenuations)?);
+        }
+        match &mut self.mode {
+            ASideMode::Analog(analog) => Ok(analog),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The analog drivers if side A is in analog mode, without switching
+    pub fn active_analog(&mut self) -> Option<&mut PinDriversAnalogA> {
+        match &mut self.mode {
+            ASideMode::Analog(analog) => Some(analog),
+            _ => None,
+        }
+    }
+
+    /// Released drivers for the self-test, which sets the pins up itself
+    pub fn self_test(&mut self) -> Result<&mut PinDriversDigitalA, EspError> {
+        self.release()?;
+        Ok(&mut self.digital)
+    }
+
+    /// Disables the pins and their pulls, ending analog mode
+    pub fn release(&mut self) -> Result<(), EspError> {
+        // Deletes the calibration schemes of the analog mode
+        self.mode = ASideMode::Released;
+        self.digital.release()
+    }
+
+    /// ADC1 channels of the analog capable pins, indexed like `AnalogReadPort`
+    pub fn adc_channels(&self) -> &[adc_channel_t; ANALOG_READ_CHANNELS] {
+        &self.channels
+    }
 }
 
 pub struct PinsB {
@@ -135,28 +417,29 @@ pub struct PinsB {
     pub b7_d: Gpio9,
 }
 
-pub struct PinDriversDigitalB<'p> {
+pub struct PinDriversDigitalB {
     //Side B
-    pub b0_d: PinDriver<'p, Gpio23, InputOutput>,
-    pub b1_d: PinDriver<'p, Gpio22, InputOutput>,
-    pub b2_d: PinDriver<'p, Gpio21, InputOutput>,
-    pub b3_d: PinDriver<'p, Gpio20, InputOutput>,
-    pub b4_d: PinDriver<'p, Gpio19, InputOutput>,
-    pub b5_d: PinDriver<'p, Gpio18, InputOutput>,
-    pub b6_d: PinDriver<'p, Gpio15, InputOutput>,
-    pub b7_d: PinDriver<'p, Gpio9, InputOutput>,
-}
-
-impl<'p> PinDriversDigitalB<'p> {
-    pub fn new(pins: &'p mut PinsB) -> Result<Self, EspError> {
-        let b0_d = PinDriver::input_output(&mut pins.b0_d)?;
-        let b1_d = PinDriver::input_output(&mut pins.b1_d)?;
-        let b2_d = PinDriver::input_output(&mut pins.b2_d)?;
-        let b3_d = PinDriver::input_output(&mut pins.b3_d)?;
-        let b4_d = PinDriver::input_output(&mut pins.b4_d)?;
-        let b5_d = PinDriver::input_output(&mut pins.b5_d)?;
-        let b6_d = PinDriver::input_output(&mut pins.b6_d)?;
-        let b7_d = PinDriver::input_output(&mut pins.b7_d)?;
+    pub b0_d: PinDriver<'static, Gpio23, InputOutput>,
+    pub b1_d: PinDriver<'static, Gpio22, InputOutput>,
+    pub b2_d: PinDriver<'static, Gpio21, InputOutput>,
+    pub b3_d: PinDriver<'static, Gpio20, InputOutput>,
+    pub b4_d: PinDriver<'static, Gpio19, InputOutput>,
+    pub b5_d: PinDriver<'static, Gpio18, InputOutput>,
+    pub b6_d: PinDriver<'static, Gpio15, InputOutput>,
+    pub b7_d: PinDriver<'static, Gpio9, InputOutput>,
+}
+
+impl PinDriversDigitalB {
+    /// Like [PinDriversDigitalA::new]
+    pub fn new(pins: PinsB) -> Result<Self, EspError> {
+        let b0_d = PinDriver::input_output(pins.b0_d)?;
+        let b1_d = PinDriver::input_output(pins.b1_d)?;
+        let b2_d = PinDriver::input_output(pins.b2_d)?;
+        let b3_d = PinDriver::input_output(pins.b3_d)?;
+        let b4_d = PinDriver::input_output(pins.b4_d)?;
+        let b5_d = PinDriver::input_output(pins.b5_d)?;
+        let b6_d = PinDriver::input_output(pins.b6_d)?;
+        let b7_d = PinDriver::input_output(pins.b7_d)?;
 
         Ok(Self {
             b0_d,
@@ -170,9 +453,9 @@ impl<'p> PinDriversDigitalB<'p> {
         })
     }
 
+    /// Like [PinDriversDigitalA::digital_read], b7_d is bit 7
     pub fn digital_read(&mut self) -> Result<u8, EspError> {
-        const POS_MASKS: [u8; 8] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80];
-        let bits: [bool; 8] = [
+        Ok(pack_levels(&[
             self.b0_d.is_high(),
             self.b1_d.is_high(),
             self.b2_d.is_high(),
@@ -181,40 +464,179 @@ impl<'p> PinDriversDigitalB<'p> {
             self.b5_d.is_high(),
             self.b6_d.is_high(),
             self.b7_d.is_high(),
-        ];
-
-        let mut result = 0;
-        for (i, entry) in bits.iter().enumerate() {
-            if *entry {
-                result |= POS_MASKS[i];
-            }
-        }
-        let result = result.reverse_bits();
-        Ok(result)
+        ]))
     }
 
     pub fn digital_write(&mut self, value: u8) -> Result<(), EspError> {
-        let mut levels = [Level::Low; 8];
-        for (i, entry) in levels.iter_mut().enumerate() {
-            *entry = if (value & (1 << i)) != 0 {
-                Level::High
-            } else {
-                Level::Low
-            };
+        let levels = unpack_levels::<8>(value);
+        self.b0_d.set_level(levels[0].into())?;
+        self.b1_d.set_level(levels[1].into())?;
+        self.b2_d.set_level(levels[2].into())?;
+        self.b3_d.set_level(levels[3].into())?;
+        self.b4_d.set_level(levels[4].into())?;
+        self.b5_d.set_level(levels[5].into())?;
+        self.b6_d.set_level(levels[6].into())?;
+        self.b7_d.set_level(levels[7].into())?;
+        Ok(())
+    }
+
+    /// Like [PinDriversDigitalA::set_direction]
+    pub fn set_direction(&mut self, outputs: u8) -> Result<(), EspError> {
+        set_direction(self.b0_d.pin(), outputs & 0x01 != 0)?;
+        set_direction(self.b1_d.pin(), outputs & 0x02 != 0)?;
+        set_direction(self.b2_d.pin(), outputs & 0x04 != 0)?;
+        set_direction(self.b3_d.pin(), outputs & 0x08 != 0)?;
+        set_direction(self.b4_d.pin(), outputs & 0x10 != 0)?;
+        set_direction(self.b5_d.pin(), outputs & 0x20 != 0)?;
+        set_direction(self.b6_d.pin(), outputs & 0x40 != 0)?;
+        set_direction(self.b7_d.pin(), outputs & 0x80 != 0)?;
+        Ok(())
+    }
+
+    /// Like [PinDriversDigitalA::set_pulls]
+    pub fn set_pulls(&mut self, pull_up: u8, pull_down: u8) -> Result<(), EspError> {
+        self.b0_d.set_pull(pull(pull_up, pull_down, 0))?;
+        self.b1_d.set_pull(pull(pull_up, pull_down, 1))?;
+        self.b2_d.set_pull(pull(pull_up, pull_down, 2))?;
+        self.b3_d.set_pull(pull(pull_up, pull_down, 3))?;
+        self.b4_d.set_pull(pull(pull_up, pull_down, 4))?;
+        self.b5_d.set_pull(pull(pull_up, pull_down, 5))?;
+        self.b6_d.set_pull(pull(pull_up, pull_down, 6))?;
+        self.b7_d.set_pull(pull(pull_up, pull_down, 7))?;
+        Ok(())
+    }
+
+    /// Like [PinDriversDigitalA::set_open_drain]
+    pub fn set_open_drain(&mut self) -> Result<(), EspError> {
+        for pin in self.pins() {
+            set_open_drain(pin)?;
         }
-        self.b0_d.set_level(levels[0])?;
-        self.b1_d.set_level(levels[1])?;
-        self.b2_d.set_level(levels[2])?;
-        self.b3_d.set_level(levels[3])?;
-        self.b4_d.set_level(levels[4])?;
-        self.b5_d.set_level(levels[5])?;
-        self.b6_d.set_level(levels[6])?;
-        self.b7_d.set_level(levels[7])?;
         Ok(())
     }
+
+    /// Like [PinDriversDigitalA::release]
+    pub fn release(&mut self) -> Result<(), EspError> {
+        release(&self.pins())
+    }
+
+    fn pins(&self) -> [i32; 8] {
+        [
+            self.b0_d.pin(),
+            self.b1_d.pin(),
+            self.b2_d.pin(),
+            self.b3_d.pin(),
+            self.b4_d.pin(),
+            self.b5_d.pin(),
+            self.b6_d.pin(),
+            self.b7_d.pin(),
+        ]
+    }
+}
+
+/// Like [ASidePinDrivers] without the analog mode
+pub struct BSidePinDrivers {
+    digital: PinDriversDigitalB,
+    /// Whether the pins are configured, they are released otherwise
+    active: bool,
+}
+
+impl BSidePinDrivers {
+    pub fn new(pins: PinsB) -> Result<Self, EspError> {
+        let mut side = Self {
+            digital: PinDriversDigitalB::new(pins)?,
+            active: false,
+        };
+        side.release()?;
+        Ok(side)
+    }
+
+    pub fn digital(
+        &mut self,
+        registers: &PortRegisters,
+    ) -> Result<&mut PinDriversDigitalB, EspError> {
+        if !self.active {
+            apply(registers, &mut self.digital)?;
+            self.active = true;
+        }
+        Ok(&mut self.digital)
+    }
+
+    pub fn self_test(&mut self) -> Result<&mut PinDriversDigitalB, EspError> {
+        self.release()?;
+        Ok(&mut self.digital)
+    }
+
+    pub fn release(&mut self) -> Result<(), EspError> {
+        self.active = false;
+        self.digital.release()
+    }
+}
+
+/// Switches of [PinsDip] in the bit order of [PinDriversDip::read]
+pub const DIP_SWITCHES: u8 = 0x01;
+
+/// DIP switch bank, each switch connects its pin to ground when turned on.
+///
+/// Only one switch fits: GPIO12 and GPIO13 are the USB-Serial/JTAG pins of the ESP32-C6 and
+/// would lose the console and flash port, every other pin is taken.
+pub struct PinsDip {
+    pub dip0: Gpio11,
 }
 
-pub enum BSidePinDrivers<'p> {
-    None,
-    Digital(PinDriversDigitalB<'p>),
+pub struct PinDriversDip {
+    pub dip0: PinDriver<'static, Gpio11, Input>,
+}
+
+impl PinDriversDip {
+    pub fn new(pins: PinsDip) -> Result<Self, EspError> {
+        let mut dip0 = PinDriver::input(pins.dip0)?;
+        dip0.set_pull(Pull::Up)?;
+
+        Ok(Self { dip0 })
+    }
+
+    /// Returns the switch positions as the B15 answers `RQ_READ_DIP_SWITCH`, a set bit means on.
+    ///
+    /// dip0 stands in for the first switch of the B15 in bit 0. Bits 1 to 7 always read as off,
+    /// host programs branching on further switches take their "off" path. Hosts learn which
+    /// bits are backed by a switch from [DIP_SWITCHES] in the capabilities.
+    pub fn read(&self) -> u8 {
+        u8::from(self.dip0.is_low())
+    }
+}
+
+/// Switches a pin of an `InputOutput` driver between both modes, the hal can only change the
+/// mode by consuming the driver. Outputs stay readable to report what they drive.
+fn set_direction(pin: i32, output: bool) -> Result<(), EspError> {
+    let mode = if output {
+        gpio_mode_t_GPIO_MODE_INPUT_OUTPUT
+    } else {
+        gpio_mode_t_GPIO_MODE_INPUT
+    };
+    esp!(unsafe { gpio_set_direction(pin, mode) })
+}
+
+fn set_open_drain(pin: i32) -> Result<(), EspError> {
+    esp!(unsafe { gpio_set_direction(pin, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD) })
+}
+
+/// Disables `pins` and their pulls with the configuration the hal resets pins to
+fn release(pins: &[i32]) -> Result<(), EspError> {
+    let config = gpio_config_t {
+        pin_bit_mask: pins.iter().fold(0, |mask, pin| mask | 1u64 << pin),
+        mode: gpio_mode_t_GPIO_MODE_DISABLE,
+        pull_up_en: gpio_pullup_t_GPIO_PULLUP_DISABLE,
+        pull_down_en: gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+        intr_type: gpio_int_type_t_GPIO_INTR_DISABLE,
+    };
+    esp!(unsafe { gpio_config(&config) })
+}
+
+fn pull(pull_up: u8, pull_down: u8, bit: u8) -> Pull {
+    match (pull_up & 1 << bit != 0, pull_down & 1 << bit != 0) {
+        (false, false) => Pull::Floating,
+        (true, false) => Pull::Up,
+        (false, true) => Pull::Down,
+        (true, true) => Pull::UpDown,
+    }
 }
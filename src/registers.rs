@@ -0,0 +1,73 @@
+//! Emulation of the AVR I/O registers B15 programs access with `setRegister`/`getRegister`
+use crate::pins::{PinDriversDigitalA, PinDriversDigitalB};
+use b32_protocol::{AvrRegister, PortRegisters};
+use esp_idf_svc::sys::EspError;
+
+/// Digital drivers of either side
+pub trait DigitalPins {
+    fn digital_read(&mut self) -> Result<u8, EspError>;
+    fn digital_write(&mut self, value: u8) -> Result<(), EspError>;
+    fn set_direction(&mut self, outputs: u8) -> Result<(), EspError>;
+    fn set_pulls(&mut self, pull_up: u8, pull_down: u8) -> Result<(), EspError>;
+}
+
+impl DigitalPins for PinDriversDigitalA {
+    fn digital_read(&mut self) -> Result<u8, EspError> {
+        self.digital_read()
+    }
+    fn digital_write(&mut self, value: u8) -> Result<(), EspError> {
+        self.digital_write(value)
+    }
+    fn set_direction(&mut self, outputs: u8) -> Result<(), EspError> {
+        self.set_direction(outputs)
+    }
+    fn set_pulls(&mut self, pull_up: u8, pull_down: u8) -> Result<(), EspError> {
+        self.set_pulls(pull_up, pull_down)
+    }
+}
+
+impl DigitalPins for PinDriversDigitalB {
+    fn digital_read(&mut self) -> Result<u8, EspError> {
+        self.digital_read()
+    }
+    fn digital_write(&mut self, value: u8) -> Result<(), EspError> {
+        self.digital_write(value)
+    }
+    fn set_direction(&mut self, outputs: u8) -> Result<(), EspError> {
+        self.set_direction(outputs)
+    }
+    fn set_pulls(&mut self, pull_up: u8, pull_down: u8) -> Result<(), EspError> {
+        self.set_pulls(pull_up, pull_down)
+    }
+}
+
+/// Brings freshly created drivers into the stored configuration
+pub fn apply(registers: &PortRegisters, pins: &mut impl DigitalPins) -> Result<(), EspError> {
+    pins.set_direction(registers.direction)?;
+    pins.digital_write(registers.output)?;
+    pins.set_pulls(registers.pull_up, registers.pull_down)
+}
+
+/// Writes `value` if given and evaluates to the register content, a write echoes `value`
+pub fn access(
+    registers: &mut PortRegisters,
+    register: AvrRegister,
+    value: Option<u8>,
+    pins: &mut impl DigitalPins,
+) -> Result<u8, EspError> {
+    let Some(value) = value else {
+        return match register {
+            AvrRegister::Pin(_) => pins.digital_read(),
+            AvrRegister::Ddr(_) => Ok(registers.direction),
+            AvrRegister::Port(_) => Ok(registers.output),
+        };
+    };
+    registers.write(register, value);
+    match register {
+        AvrRegister::Ddr(_) => pins.set_direction(registers.direction)?,
+        AvrRegister::Pin(_) | AvrRegister::Port(_) => pins.digital_write(registers.output)?,
+    }
+    // Both registers decide the pull-ups, see PortRegisters::write
+    pins.set_pulls(registers.pull_up, registers.pull_down)?;
+    Ok(value)
+}
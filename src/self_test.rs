@@ -0,0 +1,46 @@
+use crate::pins::{PinDriversDigitalA, PinDriversDigitalB};
+use esp_idf_svc::hal::delay::Ets;
+use esp_idf_svc::sys::EspError;
+
+/// Bits of the digital ports that are connected by the loopback jig.
+/// Side A only has 7 digital pins so b7_d stays unconnected.
+pub const LOOPBACK_MASK: u8 = 0x7F;
+
+/// Time the lines get to settle before they are sampled
+const SETTLE_TIME_US: u32 = 10;
+
+/// Drives a walking one from side A to side B and back.
+///
+/// Requires a jig connecting pin i of side A to pin i of side B.
+/// The receiving side is switched to open drain and released so both sides never drive
+/// against each other.
+/// Returns a bitmap per direction where a set bit means the pin passed.
+pub fn run(a: &mut PinDriversDigitalA, b: &mut PinDriversDigitalB) -> Result<(u8, u8), EspError> {
+    // Levels are latched before the modes change so no pin drives against the other side
+    b.digital_write(0xFF)?;
+    b.set_open_drain()?;
+    a.set_direction(0xFF)?;
+    let a_to_b = walk(|value| a.digital_write(value), || b.digital_read())?;
+    a.digital_write(0xFF)?;
+    a.set_open_drain()?;
+    b.set_direction(0xFF)?;
+    let b_to_a = walk(|value| b.digital_write(value), || a.digital_read())?;
+    Ok((a_to_b, b_to_a))
+}
+
+fn walk(
+    mut write: impl FnMut(u8) -> Result<(), EspError>,
+    mut read: impl FnMut() -> Result<u8, EspError>,
+) -> Result<u8, EspError> {
+    let mut passed = 0;
+    for bit in 0..LOOPBACK_MASK.count_ones() {
+        let pattern = 1 << bit;
+        write(pattern)?;
+        Ets::delay_us(SETTLE_TIME_US);
+        if read()? & LOOPBACK_MASK == pattern {
+            passed |= pattern;
+        }
+    }
+    write(0)?;
+    Ok(passed)
+}
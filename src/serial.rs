@@ -0,0 +1,33 @@
+use esp_idf_svc::hal::gpio::{Gpio16, Gpio17};
+use esp_idf_svc::hal::uart::config::{DataBits, FlowControl};
+use esp_idf_svc::hal::uart::{AsyncUartDriver, UartConfig, UartDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::sys::EspError;
+
+/// Peripherals of the host connection, kept so the driver can be created again after errors
+pub struct PinsSerial {
+    pub uart: UART1,
+    pub tx: Gpio16,
+    pub rx: Gpio17,
+}
+
+pub type Serial<'p> = AsyncUartDriver<'p, UartDriver<'p>>;
+
+/// Opens the host connection with the settings expected by B15 hosts
+pub fn open(pins: &mut PinsSerial) -> Result<Serial<'_>, EspError> {
+    let config = UartConfig::new()
+        .data_bits(DataBits::DataBits8)
+        .parity_none()
+        .baudrate(Hertz(b32_protocol::consts::BAUD))
+        .flow_control(FlowControl::None)
+        .tx_fifo_size(crate::consts::SERIAL_TX_BUFFER_SIZE);
+
+    AsyncUartDriver::new(
+        &mut pins.uart,
+        &mut pins.tx,
+        &mut pins.rx,
+        Option::<Gpio16>::None,
+        Option::<Gpio17>::None,
+        &config,
+    )
+}
@@ -0,0 +1,32 @@
+//! Settings that survive a reboot, kept in the default NVS partition
+use b32_protocol::AdcMode;
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::EspError;
+
+const NAMESPACE: &str = "b32";
+const ADC_MODE_KEY: &str = "adc_mode";
+
+pub struct Settings {
+    nvs: EspDefaultNvs,
+}
+
+impl Settings {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        Ok(Self {
+            nvs: EspDefaultNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    /// Falls back to the default if nothing or an unknown mode was stored
+    pub fn adc_mode(&self) -> Result<AdcMode, EspError> {
+        Ok(self
+            .nvs
+            .get_u8(ADC_MODE_KEY)?
+            .and_then(AdcMode::from_u8)
+            .unwrap_or_default())
+    }
+
+    pub fn set_adc_mode(&mut self, mode: AdcMode) -> Result<(), EspError> {
+        self.nvs.set_u8(ADC_MODE_KEY, mode as u8)
+    }
+}
@@ -0,0 +1,154 @@
+//! Continuous sampling of side A with the DMA driven ADC
+use crate::adc::OneshotAdc;
+use crate::pins::ANALOG_READ_CHANNELS;
+use b32_protocol::consts::MAX_STREAM_BLOCK_SAMPLES;
+use b32_protocol::{AnalogReadPort, StreamSample};
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicU32, Ordering};
+use esp_idf_svc::hal::adc::attenuation::adc_atten_t;
+use esp_idf_svc::hal::adc::continuous::config::Config;
+use esp_idf_svc::hal::adc::continuous::{AdcChannels, AdcDriver, AdcMeasurement};
+use esp_idf_svc::hal::adc::ADC1;
+use esp_idf_svc::hal::delay;
+use esp_idf_svc::hal::interrupt::asynch::HalIsrNotification;
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::sys::{
+    adc_channel_t, adc_continuous_evt_cbs_t, adc_continuous_evt_data_t, adc_continuous_handle_t,
+    adc_continuous_register_event_callbacks, esp, EspError, ESP_ERR_TIMEOUT,
+};
+
+const PORTS: [AnalogReadPort; ANALOG_READ_CHANNELS] = [
+    AnalogReadPort::Port1,
+    AnalogReadPort::Port2,
+    AnalogReadPort::Port3,
+    AnalogReadPort::Port4,
+    AnalogReadPort::Port5,
+    AnalogReadPort::Port6,
+];
+
+/// Wakes [AdcStream::read], stands in for the notifier of the hal whose callbacks are replaced
+static CONVERSIONS: HalIsrNotification = HalIsrNotification::new();
+/// Frames the driver threw away since the stream started because its pool was full
+static POOL_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn on_conv_done(
+    _handle: adc_continuous_handle_t,
+    _data: *const adc_continuous_evt_data_t,
+    _user_data: *mut c_void,
+) -> bool {
+    CONVERSIONS.notify_lsb()
+}
+
+extern "C" fn on_pool_ovf(
+    _handle: adc_continuous_handle_t,
+    _data: *const adc_continuous_evt_data_t,
+    _user_data: *mut c_void,
+) -> bool {
+    POOL_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    CONVERSIONS.notify_lsb()
+}
+
+/// Channels picked by the host at runtime, the drivers of the hal only know them at compile time
+struct StreamChannels(Vec<(adc_channel_t, adc_atten_t)>);
+
+impl AdcChannels for StreamChannels {
+    type Adc = ADC1;
+    type Iterator<'a> = core::iter::Copied<core::slice::Iter<'a, (adc_channel_t, adc_atten_t)>>;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.0.iter().copied()
+    }
+}
+
+/// Continuous conversion of ADC1, borrowed from the oneshot unit for the length of the stream
+pub struct AdcStream<'a> {
+    driver: AdcDriver<'a>,
+    /// Port of every channel of `channels` passed to [Self::start]
+    ports: Vec<(adc_channel_t, AnalogReadPort)>,
+    buffer: [AdcMeasurement; MAX_STREAM_BLOCK_SAMPLES as usize],
+}
+
+impl<'a> AdcStream<'a> {
+    /// Starts converting the ports selected by the bits of `ports`
+    ///
+    /// `channels` and `attenuations` are indexed like [AnalogReadPort], the pins have to be in
+    /// analog mode already. The oneshot unit stays borrowed until the stream is dropped.
+    pub fn start(
+        oneshot: &'a mut OneshotAdc<'_>,
+        ports: u8,
+        sample_rate: u32,
+        channels: &[adc_channel_t; ANALOG_READ_CHANNELS],
+        attenuations: &[adc_atten_t; ANALOG_READ_CHANNELS],
+    ) -> Result<Self, EspError> {
+        let selected = PORTS
+            .iter()
+            .zip(channels.iter().zip(attenuations))
+            .filter(|(port, _)| ports & (1 << **port as u8) != 0);
+        let pattern = selected
+            .clone()
+            .map(|(_, (channel, attenuation))| (*channel, *attenuation))
+            .collect();
+        let config = Config::new()
+            .sample_freq(Hertz(sample_rate))
+            .frame_measurements(MAX_STREAM_BLOCK_SAMPLES as usize);
+        let mut driver = AdcDriver::new(oneshot.lend(), &config, StreamChannels(pattern))?;
+        // Callbacks can only be registered before the start. ADC1 streams one set of ports at a
+        // time, so the counter can be global.
+        POOL_OVERFLOWS.store(0, Ordering::Relaxed);
+        CONVERSIONS.reset();
+        let callbacks = adc_continuous_evt_cbs_t {
+            on_conv_done: Some(on_conv_done),
+            on_pool_ovf: Some(on_pool_ovf),
+        };
+        esp!(unsafe {
+            adc_continuous_register_event_callbacks(
+                driver.handle(),
+                &callbacks,
+                core::ptr::null_mut(),
+            )
+        })?;
+        driver.start()?;
+        Ok(Self {
+            driver,
+            ports: selected
+                .map(|(port, (channel, _))| (*channel, *port))
+                .collect(),
+            buffer: [AdcMeasurement::INIT; MAX_STREAM_BLOCK_SAMPLES as usize],
+        })
+    }
+
+    /// Waits for the next conversion frame
+    pub async fn read(&mut self) -> Result<Vec<StreamSample>, EspError> {
+        // `read_async` of the hal waits for its own notifier, which no longer gets woken
+        let count = loop {
+            match self.driver.read(&mut self.buffer, delay::NON_BLOCK) {
+                Ok(count) if count > 0 => break count,
+                Err(err) if err.code() != ESP_ERR_TIMEOUT => return Err(err),
+                _ => {
+                    CONVERSIONS.wait().await;
+                }
+            }
+        };
+        Ok(self.buffer[..count]
+            .iter()
+            .filter_map(|measurement| {
+                let (_, port) = self
+                    .ports
+                    .iter()
+                    .find(|(channel, _)| *channel == measurement.channel())?;
+                Some(StreamSample {
+                    port: *port,
+                    value: measurement.data(),
+                })
+            })
+            .collect())
+    }
+
+    /// Samples lost since the start because they were not read in time. A full pool drops
+    /// whole frames of [MAX_STREAM_BLOCK_SAMPLES].
+    pub fn dropped(&self) -> u32 {
+        POOL_OVERFLOWS
+            .load(Ordering::Relaxed)
+            .saturating_mul(MAX_STREAM_BLOCK_SAMPLES as u32)
+    }
+}